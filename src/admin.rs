@@ -0,0 +1,346 @@
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, Request, State},
+    http::{
+        header::{AUTHORIZATION, HOST, ORIGIN, WWW_AUTHENTICATE},
+        Method, StatusCode,
+    },
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    app_config::{list_installation_ids, AppConfig, RepositoryConfig},
+    app_errors::AppErrors,
+    audit_log::{read_audit_entries, AuditEntry, AuditFilter},
+    dashboard,
+    delivery_store::{list_deliveries, DeliverySummary},
+    replay_delivery,
+    server::ClientCertificate,
+};
+
+type AdminResult<T> = Result<Json<T>, (StatusCode, String)>;
+
+#[derive(Serialize)]
+pub struct InstallationSummary {
+    pub installation_id: u128,
+}
+
+#[derive(Deserialize)]
+pub struct LimitQuery {
+    pub limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct ReplayQuery {
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Serialize)]
+pub struct ReplayResponse {
+    pub outcome: &'static str,
+    pub description: String,
+}
+
+fn error_response(err: anyhow::Error) -> (StatusCode, String) {
+    let status = match err.downcast_ref::<AppErrors>() {
+        Some(AppErrors::DeliveryNotFound(_)) => StatusCode::NOT_FOUND,
+        Some(_) => StatusCode::BAD_REQUEST,
+        None => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, err.to_string())
+}
+
+fn not_found(installation_id: u128) -> (StatusCode, String) {
+    (
+        StatusCode::NOT_FOUND,
+        format!("No config for installation {installation_id}"),
+    )
+}
+
+fn tokens_match(provided: &str, expected: &str) -> bool {
+    provided.len() == expected.len()
+        && provided
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+// Token sent as `Bearer <token>` by scripts or as the password of a `Basic` login by browsers
+fn provided_token(request: &Request) -> Option<String> {
+    let value = request.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    if let Some(token) = value.strip_prefix("Bearer ") {
+        return Some(token.to_string());
+    }
+    let credentials = STANDARD.decode(value.strip_prefix("Basic ")?).ok()?;
+    let credentials = String::from_utf8(credentials).ok()?;
+    let (_, password) = credentials.split_once(':')?;
+    Some(password.to_string())
+}
+
+// Browsers replay cached Basic credentials and client certificates on cross-site form posts,
+// so a request changing state must not come from a page served by another host
+fn is_cross_origin(request: &Request) -> bool {
+    if matches!(*request.method(), Method::GET | Method::HEAD) {
+        return false;
+    }
+    let Some(origin) = request.headers().get(ORIGIN) else {
+        return false;
+    };
+    let origin_host = origin
+        .to_str()
+        .ok()
+        .and_then(|origin| origin.split_once("://"))
+        .map(|(_, host)| host);
+    let host = request
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok());
+    origin_host.is_none() || origin_host != host
+}
+
+// Admin routes are disabled until an `admin_token` or a TLS `client_ca_path` is configured,
+// a verified client certificate is enough on its own
+pub async fn require_admin(
+    State(app_config): State<AppConfig>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let client_ca = app_config
+        .tls
+        .as_ref()
+        .is_some_and(|tls| tls.client_ca_path.is_some());
+    if !client_ca && app_config.admin_token.is_none() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    if is_cross_origin(&request) {
+        return (StatusCode::FORBIDDEN, "Cross-origin request").into_response();
+    }
+    if client_ca && request.extensions().get::<ClientCertificate>().is_some() {
+        return next.run(request).await;
+    }
+    match (provided_token(&request), &app_config.admin_token) {
+        (Some(token), Some(expected)) if tokens_match(&token, expected) => next.run(request).await,
+        _ => (
+            StatusCode::UNAUTHORIZED,
+            [(WWW_AUTHENTICATE, "Basic realm=\"admin\"")],
+        )
+            .into_response(),
+    }
+}
+
+pub fn admin_router(app_config: AppConfig) -> Router<AppConfig> {
+    Router::new()
+        .route("/audit", get(audit_entries))
+        .route("/dashboard", get(dashboard::dashboard))
+        .route("/installations", get(list_installations))
+        .route(
+            "/installations/:installation_id/config",
+            get(get_repository_config)
+                .put(put_repository_config)
+                .delete(delete_repository_config),
+        )
+        .route("/deliveries", get(recent_deliveries))
+        .route("/deliveries/:delivery_id/replay", post(replay))
+        .route_layer(middleware::from_fn_with_state(app_config, require_admin))
+}
+
+pub async fn audit_entries(Query(filter): Query<AuditFilter>) -> AdminResult<Vec<AuditEntry>> {
+    let entries = read_audit_entries(&filter).map_err(error_response)?;
+    Ok(Json(entries))
+}
+
+pub async fn list_installations() -> AdminResult<Vec<InstallationSummary>> {
+    let installations = list_installation_ids()
+        .map_err(error_response)?
+        .into_iter()
+        .map(|installation_id| InstallationSummary { installation_id })
+        .collect();
+    Ok(Json(installations))
+}
+
+pub async fn get_repository_config(
+    Path(installation_id): Path<u128>,
+) -> AdminResult<RepositoryConfig> {
+    match RepositoryConfig::read(installation_id) {
+        Ok(config) => Ok(Json(config)),
+        Err(_) => Err(not_found(installation_id)),
+    }
+}
+
+// The body is rejected with a 400 when it is not JSON, has unknown fields or does not pass
+// `RepositoryConfig::validate`
+pub async fn put_repository_config(
+    Path(installation_id): Path<u128>,
+    body: Bytes,
+) -> AdminResult<RepositoryConfig> {
+    let config = serde_json::from_slice::<RepositoryConfig>(&body)
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    config.validate().map_err(error_response)?;
+    config.save(installation_id).map_err(error_response)?;
+    Ok(Json(config))
+}
+
+pub async fn delete_repository_config(
+    Path(installation_id): Path<u128>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    match RepositoryConfig::delete(installation_id).map_err(error_response)? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(not_found(installation_id)),
+    }
+}
+
+pub async fn recent_deliveries(
+    Query(query): Query<LimitQuery>,
+) -> AdminResult<Vec<DeliverySummary>> {
+    let deliveries = list_deliveries(query.limit.unwrap_or(50)).map_err(error_response)?;
+    Ok(Json(deliveries))
+}
+
+pub async fn replay(
+    State(mut app_config): State<AppConfig>,
+    Path(delivery_id): Path<String>,
+    Query(query): Query<ReplayQuery>,
+) -> AdminResult<ReplayResponse> {
+    app_config.dry_run |= query.dry_run;
+    let outcome = replay_delivery(app_config, &delivery_id)
+        .await
+        .map_err(error_response)?;
+    Ok(Json(ReplayResponse {
+        outcome: outcome.metric_label(),
+        description: outcome.describe(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::{to_bytes, Body};
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::storage::init_test_storage;
+
+    fn app() -> Router {
+        init_test_storage();
+        let app_config = AppConfig {
+            admin_token: Some("secret".to_string()),
+            ..AppConfig::default()
+        };
+        Router::new()
+            .nest("/admin", admin_router(app_config.clone()))
+            .with_state(app_config)
+    }
+
+    fn request(method: Method, uri: &str) -> axum::http::request::Builder {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(HOST, "bumper.example.com")
+            .header(AUTHORIZATION, "Bearer secret")
+    }
+
+    async fn send(request: Request) -> (StatusCode, String) {
+        let response = app().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    fn config_body(branch_ref: &str) -> Body {
+        Body::from(
+            json!({
+                "commit_when_sender_is_bot": false,
+                "file_to_donwload": "Cargo.toml",
+                "pattern_version_to_search": "version = ",
+                "branch_refs_to_observe": [branch_ref]
+            })
+            .to_string(),
+        )
+    }
+
+    #[tokio::test]
+    async fn requires_the_admin_token() {
+        let request = Request::builder()
+            .uri("/admin/installations")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _) = send(request).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let request = Request::builder()
+            .uri("/admin/installations")
+            .header(AUTHORIZATION, "Bearer wrong")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _) = send(request).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_repository_configs() {
+        let uri = "/admin/installations/9001/config";
+        for body in [
+            Body::from("{\"file_to_donwload\":"),
+            Body::from("{\"unknown\":true}"),
+            config_body("main"),
+        ] {
+            let (status, _) = send(request(Method::PUT, uri).body(body).unwrap()).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+        let (status, _) = send(request(Method::GET, uri).body(Body::empty()).unwrap()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn puts_reads_and_deletes_a_repository_config() {
+        let uri = "/admin/installations/9002/config";
+        let put = request(Method::PUT, uri)
+            .body(config_body("refs/heads/main"))
+            .unwrap();
+        let (status, _) = send(put).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = send(request(Method::GET, uri).body(Body::empty()).unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        let config: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(config["branch_refs_to_observe"], json!(["refs/heads/main"]));
+        assert_eq!(config["file_to_donwload"], json!("Cargo.toml"));
+
+        let delete = || request(Method::DELETE, uri).body(Body::empty()).unwrap();
+        assert_eq!(send(delete()).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(send(delete()).await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn rejects_cross_origin_posts() {
+        let replay = |origin: Option<&'static str>| async move {
+            let mut request = Request::builder()
+                .method(Method::POST)
+                .uri("/admin/deliveries/d-1/replay")
+                .header(HOST, "bumper.example.com")
+                .header(AUTHORIZATION, "Basic YWRtaW46c2VjcmV0");
+            if let Some(origin) = origin {
+                request = request.header(ORIGIN, origin);
+            }
+            send(request.body(Body::empty()).unwrap()).await.0
+        };
+        assert_eq!(
+            replay(Some("https://evil.example.org")).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(replay(Some("null")).await, StatusCode::FORBIDDEN);
+        // Past the check, the delivery simply does not exist
+        assert_eq!(
+            replay(Some("https://bumper.example.com")).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(replay(None).await, StatusCode::NOT_FOUND);
+    }
+}
@@ -7,10 +7,14 @@ use reqwest::header::{HeaderValue, ACCEPT, AUTHORIZATION, USER_AGENT};
 use reqwest::{Client, ClientBuilder, StatusCode};
 use serde::Deserialize;
 use serde_json::json;
+use std::sync::OnceLock;
+use tracing::instrument;
 
 use crate::app_config::SecurityConfig;
 use crate::app_errors::AppErrors;
-use crate::installation_token_data::InstallationToken;
+use crate::installation_token_data::{InstallationToken, TokenScope};
+use crate::metrics::API_CALL_DURATION;
+use crate::version_bump::{commit_message, increase_version_in_content};
 
 #[allow(dead_code)] //method used for testing if app is valid
 #[derive(Deserialize)]
@@ -22,6 +26,7 @@ pub struct AuthenticatedAppData {
 
 #[derive(Deserialize)]
 pub struct FileConteAppDataApi {
+    #[allow(dead_code)]
     #[serde(rename = "type")]
     pub type_: String,
     pub encoding: String,
@@ -32,9 +37,12 @@ pub struct FileConteAppDataApi {
 }
 
 pub struct FileConteAppDataDecoded {
+    #[allow(dead_code)]
     pub name: String,
     pub path: String,
     pub content: String,
+    pub old_content: String,
+    pub old_version: String,
     pub new_version: String,
 }
 
@@ -46,7 +54,7 @@ pub struct GithubTreeData {
 
 #[derive(Deserialize)]
 pub struct GithubCommitData {
-    sha: String,
+    pub sha: String,
     //url:String,
 }
 
@@ -73,50 +81,39 @@ impl FileConteAppDataApi {
 
     pub fn increase_version(
         self,
-        pattern_version_to_search: &String,
+        pattern_version_to_search: &str,
     ) -> Result<FileConteAppDataDecoded> {
-        let Some(version_pos) = self.content.find(pattern_version_to_search) else {
-            let err = format!("Could not find pattern: {}", pattern_version_to_search);
-            bail!(AppErrors::FailedToIncreaseVersionInFile(err));
-        };
-
-        let mut endline_pos = self.content.as_str()[version_pos..].find('\n');
-        if endline_pos.is_none() {
-            endline_pos = Some(self.size as usize);
-        }
-        let mut endline_pos = endline_pos.unwrap();
-        endline_pos += version_pos;
-
-        let version_line = &self.content.as_str()[version_pos..endline_pos];
-        let actual_version = &self.content.as_str()
-            [version_pos + pattern_version_to_search.len()..endline_pos]
-            .trim_matches(|c| c == ' ' || c == '"');
-
-        let mut version_split: Vec<&str> = actual_version.split('.').collect();
-        ensure!(
-            version_split.len() == 3,
-            AppErrors::FailedToIncreaseVersionInFile(
-                "Failed to obtain version in format: MAJOR.MINOR.PATCH".to_string()
-            )
-        );
-        let temp_mid_string = (version_split[1].parse::<u32>()? + 1).to_string();
-        version_split[1] = temp_mid_string.as_str();
-
-        let version_split = version_split.join(".");
-        let final_version = format!("{} \"{}\"", pattern_version_to_search, version_split);
-        let new_content = self.content.replace(version_line, &final_version);
+        let bump = increase_version_in_content(&self.content, pattern_version_to_search)?;
 
         let result = FileConteAppDataDecoded {
             name: self.name,
             path: self.path,
-            content: new_content,
-            new_version: version_split,
+            content: bump.content,
+            old_content: self.content,
+            old_version: bump.old_version,
+            new_version: bump.new_version,
         };
 
         Ok(result)
     }
 }
 
+static GITHUB_API_URL: &str = "https://api.github.com";
+// Only set by the tests, to send the calls to a local stub of the API
+static API_URL_OVERRIDE: OnceLock<String> = OnceLock::new();
+
+fn api_url(path: &str) -> String {
+    let base = API_URL_OVERRIDE
+        .get()
+        .map_or(GITHUB_API_URL, String::as_str);
+    format!("{base}{path}")
+}
+
+#[cfg(test)]
+pub fn use_api_stub(url: &str) {
+    API_URL_OVERRIDE.get_or_init(|| url.to_string());
+}
+
 fn get_client_with_default_headers(jwt_token: Option<&str>) -> Result<Client, reqwest::Error> {
     let mut headers = HeaderMap::new();
     headers.insert(
@@ -137,6 +134,8 @@ fn get_client_with_default_headers(jwt_token: Option<&str>) -> Result<Client, re
         "X-GitHub-Api-Version",
         HeaderValue::from_static("2022-11-28"),
     );
+    #[cfg(feature = "otel")]
+    crate::telemetry::inject_trace_context(&mut headers);
 
     let client = ClientBuilder::new().default_headers(headers).build()?;
     Ok(client)
@@ -145,7 +144,7 @@ fn get_client_with_default_headers(jwt_token: Option<&str>) -> Result<Client, re
 //Get the authenticated app from https://docs.github.com/en/rest/apps/apps?apiVersion=2022-11-28#get-the-authenticated-app
 async fn get_app_info_impl(jwt_token: &str) -> Result<AuthenticatedAppData, reqwest::Error> {
     let client = get_client_with_default_headers(Some(jwt_token))?;
-    let response = client.get("https://api.github.com/app").send().await?;
+    let response = client.get(api_url("/app")).send().await?;
 
     let data = response.json::<AuthenticatedAppData>().await?;
 
@@ -153,7 +152,11 @@ async fn get_app_info_impl(jwt_token: &str) -> Result<AuthenticatedAppData, reqw
 }
 
 #[allow(dead_code)] //method used for testing if app is valid
+#[instrument(skip_all)]
 pub async fn get_app_info(jwt_token: &str) -> Result<AuthenticatedAppData> {
+    let _timer = API_CALL_DURATION
+        .with_label_values(&["get_app_info"])
+        .start_timer();
     match get_app_info_impl(jwt_token).await {
         Ok(result) => Ok(result),
         Err(err) => bail!(AppErrors::ApiFailure(
@@ -164,12 +167,16 @@ pub async fn get_app_info(jwt_token: &str) -> Result<AuthenticatedAppData> {
 }
 
 pub async fn get_access_token_impl(
-    installation_id: u128,
+    scope: &TokenScope,
     jwt_token: &str,
 ) -> Result<InstallationToken, reqwest::Error> {
     let client = get_client_with_default_headers(Some(jwt_token))?;
-    let link = format!("https://api.github.com/app/installations/{installation_id}/access_tokens");
-    let response = client.post(link).send().await?;
+    let link = api_url(&format!(
+        "/app/installations/{}/access_tokens",
+        scope.installation_id
+    ));
+    let response = client.post(link).json(scope).send().await?;
+    let response = response.error_for_status()?;
 
     let data = response.json::<InstallationToken>().await?;
 
@@ -177,8 +184,12 @@ pub async fn get_access_token_impl(
 }
 
 //https://docs.github.com/en/rest/apps/apps?apiVersion=2022-11-28#get-an-installation-for-the-authenticated-app
-pub async fn get_access_token(installation_id: u128, jwt_token: &str) -> Result<InstallationToken> {
-    match get_access_token_impl(installation_id, jwt_token).await {
+#[instrument(skip_all)]
+pub async fn get_access_token(scope: &TokenScope, jwt_token: &str) -> Result<InstallationToken> {
+    let _timer = API_CALL_DURATION
+        .with_label_values(&["get_access_token"])
+        .start_timer();
+    match get_access_token_impl(scope, jwt_token).await {
         Ok(result) => Ok(result),
         Err(err) => bail!(AppErrors::ApiFailure(
             "get_access_token",
@@ -194,8 +205,9 @@ pub async fn get_repo_file_content_impl(
     file_path: &String,
 ) -> Result<FileConteAppDataApi, reqwest::Error> {
     let client = get_client_with_default_headers(Some(token))?;
-    let link =
-        format!("https://api.github.com/repos/{repo_owner}/{repo_name}/contents/{file_path}");
+    let link = api_url(&format!(
+        "/repos/{repo_owner}/{repo_name}/contents/{file_path}"
+    ));
     let response = client.get(link).send().await?;
     let response = response.error_for_status()?;
 
@@ -204,13 +216,17 @@ pub async fn get_repo_file_content_impl(
 }
 
 //https://docs.github.com/en/rest/repos/contents?apiVersion=2022-11-28
+#[instrument(skip_all)]
 pub async fn get_repo_file_content(
     token: &str,
     repo_owner: &String,
     repo_name: &String,
     file_path: &String,
-    pattern_version_to_search: &String,
+    pattern_version_to_search: &str,
 ) -> Result<FileConteAppDataDecoded> {
+    let _timer = API_CALL_DURATION
+        .with_label_values(&["get_repo_file_content"])
+        .start_timer();
     match get_repo_file_content_impl(token, repo_owner, repo_name, file_path).await {
         Ok(mut result) => {
             result.decode_file()?;
@@ -244,7 +260,7 @@ async fn create_tree_impl(
     });
 
     let client = get_client_with_default_headers(Some(token))?;
-    let link = format!("https://api.github.com/repos/{repo_owner}/{repo_name}/git/trees");
+    let link = api_url(&format!("/repos/{repo_owner}/{repo_name}/git/trees"));
     let response = client.post(link).json(&body_data).send().await?;
     let status_code = response.status();
 
@@ -253,6 +269,7 @@ async fn create_tree_impl(
 }
 
 // https://docs.github.com/en/rest/git/trees?apiVersion=2022-11-28
+#[instrument(skip_all)]
 pub async fn create_tree(
     token: &str,
     repo_owner: &String,
@@ -260,6 +277,9 @@ pub async fn create_tree(
     base_tree: &String,
     file_content: &FileConteAppDataDecoded,
 ) -> Result<GithubTreeData> {
+    let _timer = API_CALL_DURATION
+        .with_label_values(&["create_tree"])
+        .start_timer();
     match create_tree_impl(token, repo_owner, repo_name, base_tree, file_content).await {
         Ok((result, status_code)) => {
             if status_code != StatusCode::CREATED {
@@ -287,12 +307,12 @@ async fn create_commit_impl(
     let client = get_client_with_default_headers(Some(token))?;
 
     let body_data = json!({
-        "message": format!("Increase version to {}",file_content.new_version),
+        "message": commit_message(&file_content.new_version),
         "parents": [commit],
         "tree": tree_data.sha,
     });
 
-    let link = format!("https://api.github.com/repos/{repo_owner}/{repo_name}/git/commits");
+    let link = api_url(&format!("/repos/{repo_owner}/{repo_name}/git/commits"));
     let response = client.post(link).json(&body_data).send().await?;
     let status_code = response.status();
 
@@ -301,6 +321,7 @@ async fn create_commit_impl(
 }
 
 //https://docs.github.com/en/rest/git/commits
+#[instrument(skip_all)]
 pub async fn create_commit(
     token: &str,
     repo_owner: &String,
@@ -309,6 +330,9 @@ pub async fn create_commit(
     file_content: &FileConteAppDataDecoded,
     tree_data: &GithubTreeData,
 ) -> Result<GithubCommitData> {
+    let _timer = API_CALL_DURATION
+        .with_label_values(&["create_commit"])
+        .start_timer();
     match create_commit_impl(
         token,
         repo_owner,
@@ -349,12 +373,13 @@ async fn update_a_refence_impl(
         "force": true
     });
 
-    let link = format!("https://api.github.com/repos/{repo_owner}/{repo_name}/git/{ref_to_use}");
+    let link = api_url(&format!("/repos/{repo_owner}/{repo_name}/git/{ref_to_use}"));
     let _ = client.post(link).json(&body_data).send().await?;
     Ok(())
 }
 
 //https://docs.github.com/en/rest/git/refs?apiVersion=2022-11-28
+#[instrument(skip_all)]
 pub async fn update_a_refence(
     token: &str,
     repo_owner: &String,
@@ -362,6 +387,9 @@ pub async fn update_a_refence(
     commit_data: &GithubCommitData,
     ref_to_use: &String,
 ) -> Result<()> {
+    let _timer = API_CALL_DURATION
+        .with_label_values(&["update_a_refence"])
+        .start_timer();
     match update_a_refence_impl(token, repo_owner, repo_name, commit_data, ref_to_use).await {
         Ok(()) => Ok(()),
         Err(err) => bail!(AppErrors::ApiFailure(
@@ -374,7 +402,7 @@ pub async fn update_a_refence(
 async fn get_github_environment_details_impl() -> Result<GithubMetaDetails, reqwest::Error> {
     let client = get_client_with_default_headers(None)?;
 
-    let link = "https://api.github.com/meta";
+    let link = api_url("/meta");
     let response = client.get(link).send().await?;
 
     let data = response.json::<GithubMetaDetails>().await?;
@@ -382,7 +410,11 @@ async fn get_github_environment_details_impl() -> Result<GithubMetaDetails, reqw
 }
 
 //https://api.github.com/meta
+#[instrument(skip_all)]
 pub async fn get_github_environment_details() -> Result<SecurityConfig> {
+    let _timer = API_CALL_DURATION
+        .with_label_values(&["get_github_environment_details"])
+        .start_timer();
     match get_github_environment_details_impl().await {
         Ok(meta_details) => {
             let subnets: Vec<IpNet> = meta_details
@@ -4,201 +4,619 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use anyhow::{bail, Result};
+use anyhow::{bail, ensure, Result};
+use axum::http::HeaderMap;
+use chrono::{DateTime, Utc};
 use ipnet::IpNet;
-use log::info;
+use jsonwebtoken::EncodingKey;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tracing::info;
 
-use crate::{app_errors::AppErrors, installation_token_data::create_token_folder};
+use crate::{
+    app_errors::{AppErrors, ConfigProblem},
+    client_ip::resolve_client_ip,
+    local_git::create_local_repos_folder,
+    secrets::{lookup_or_file, SecretProviderConfig},
+    storage::storage,
+};
 pub static WEBHOOK_COMMIT_TYPE_BOT: &str = "Bot";
-pub static CONFIG_FILE_APP: &str = "IncreaseAppVersion.json";
-
-static EXPECTED_ENV_VARS: [&str; 7] = [
-    "CALLBACK_SECRET_TOKEN",
-    "APP_NAME",
-    "PRIVATE_KEY_FILE_LOC",
-    "APP_ID",
-    "COMMIT_WHEN_SENDER_IS_BOT",
-    "FILE_TO_DOWNLOAD",
-    "PATTERN_VERSION_TO_SEARCH",
+pub static CURRENT_CALLBACK_SECRET: &str = "current";
+
+static REDACTED: &str = "<redacted>";
+
+// Fields each overridden by their own environment variable, lists are comma separated. Every
+// variable can also be given as a path in `<NAME>_FILE`
+static ENV_OVERRIDES: [(&str, &str); 15] = [
+    ("CALLBACK_SECRET_TOKEN", "callback_token"),
+    ("APP_NAME", "app_name"),
+    ("PRIVATE_KEY_FILE_LOC", "private_key_file"),
+    ("APP_ID", "app_id"),
+    ("COMMIT_WHEN_SENDER_IS_BOT", "commit_when_sender_is_bot"),
+    ("FILE_TO_DOWNLOAD", "file_to_download"),
+    ("PATTERN_VERSION_TO_SEARCH", "pattern_version_to_search"),
+    ("BRANCH_REFS_TO_OBSERVE", "branch_refs_to_observe"),
+    ("WHITELIST_IPS", "whitelist_ips"),
+    ("DENY_IPS", "deny_ips"),
+    ("TRUSTED_PROXIES", "trusted_proxies"),
+    ("DRY_RUN", "dry_run"),
+    ("DELIVERY_RETENTION_DAYS", "delivery_retention_days"),
+    (
+        "HOOK_SUBNETS_REFRESH_MINUTES",
+        "hook_subnets_refresh_minutes",
+    ),
+    (
+        "TOKEN_REFRESH_MARGIN_SECONDS",
+        "token_refresh_margin_seconds",
+    ),
 ];
 
-static CONFIG_DATA_PATH: &str = "config";
+// Where the config comes from, each layer overrides the fields set by the previous one: the
+// defaults, the config file, the environment, then the command line flags
+#[derive(Default)]
+pub struct ConfigSources {
+    // TOML, YAML or JSON file, `config/IncreaseAppVersion.json` of the storage when unset
+    pub file: Option<PathBuf>,
+    pub flags: Map<String, Value>,
+}
+
 pub fn create_app_folder() -> Result<()> {
-    if !Path::new(CONFIG_DATA_PATH).exists() {
-        fs::create_dir(CONFIG_DATA_PATH)?;
-    }
-    create_token_folder()?;
+    storage().prepare()?;
+    create_local_repos_folder()?;
     Ok(())
 }
 
 #[derive(Clone, Serialize, Deserialize)] //Clone needed by axum state
 pub struct AppConfig {
+    // Secrets are read from the config file but never written back to it
+    #[serde(default, skip_serializing)]
     pub callback_token: String,
+    #[serde(default, skip_serializing)]
+    pub previous_callback_tokens: Vec<CallbackSecret>,
     pub app_name: String,
+    #[serde(default, skip_serializing)]
     pub private_signature: String,
+    // PEM file read into `private_signature`
+    #[serde(default)]
+    pub private_key_file: Option<String>,
     pub app_id: u128,
     pub whitelist_ips: Vec<String>,
+    #[serde(default)]
+    pub deny_ips: Vec<String>,
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    #[serde(default)]
+    pub proxy_protocol: bool,
 
     pub commit_when_sender_is_bot: bool,
     pub file_to_download: String,
     pub pattern_version_to_search: String,
     pub branch_refs_to_observe: Vec<String>,
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default = "default_delivery_retention_days")]
+    pub delivery_retention_days: u32,
+    #[serde(default, skip_serializing)]
+    pub admin_token: Option<String>,
+    // Master secret of the installation token files, stored in plaintext when unset
+    #[serde(default, skip_serializing)]
+    pub token_encryption_key: Option<String>,
+    #[serde(default = "default_hook_subnets_refresh_minutes")]
+    pub hook_subnets_refresh_minutes: u64,
+    #[serde(default = "default_token_refresh_margin_seconds")]
+    pub token_refresh_margin_seconds: u64,
+    #[serde(default)]
+    pub local_repositories: Vec<LocalRepositoryConfig>,
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub signature_policy: SignaturePolicy,
+    #[serde(default)]
+    pub secret_provider: SecretProviderConfig,
+}
+
+fn default_delivery_retention_days() -> u32 {
+    14
+}
+
+fn default_hook_subnets_refresh_minutes() -> u64 {
+    60
+}
+
+fn default_token_refresh_margin_seconds() -> u64 {
+    300
+}
+
+// Repository hosted on a plain git server, bumped through a local clone instead of the GitHub API
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LocalRepositoryConfig {
+    pub name: String,
+    pub remote_url: String,
+    pub ssh_key_path: Option<String>,
+    pub file_to_download: String,
+    pub pattern_version_to_search: String,
+    pub branch_refs_to_observe: Vec<String>,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+// Older webhook secret still accepted while a rotation is rolled out to GitHub and the git servers
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CallbackSecret {
+    pub name: String,
+    pub token: String,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+// Signature headers a GitHub delivery has to carry, the SHA-1 one is only checked when required
+// or used as a fallback
+#[derive(Clone, Copy, Default, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SignaturePolicy {
+    #[default]
+    Sha256,
+    Sha256OrSha1,
+    Both,
+}
+
+// PEM files served over HTTPS, reloaded when they change on disk
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    // CA of the client certificates accepted by the admin routes
+    #[serde(default)]
+    pub client_ca_path: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct RepositoryConfig {
     pub commit_when_sender_is_bot: bool,
     pub file_to_donwload: String,
     pub pattern_version_to_search: String,
     pub branch_refs_to_observe: Vec<String>,
+    #[serde(default)]
+    pub dry_run: bool,
 }
 
 impl RepositoryConfig {
-    fn generate_default_config(file_name: &str, app_config: &AppConfig) -> RepositoryConfig {
+    fn generate_default_config(installation_id: u128, app_config: &AppConfig) -> RepositoryConfig {
         let config = RepositoryConfig {
             commit_when_sender_is_bot: app_config.commit_when_sender_is_bot,
             file_to_donwload: app_config.file_to_download.clone(),
             pattern_version_to_search: app_config.pattern_version_to_search.clone(),
             branch_refs_to_observe: app_config.branch_refs_to_observe.clone(),
+            dry_run: false,
         };
         let data = serde_json::to_string(&config).expect("failed to convert RepositoryConfig");
 
-        let file_path = get_config_full_path_file(file_name);
-        fs::write(file_path, data).expect("coudl not write RepositoryConfig");
-        info!("Repo config file {file_name} not found, will generate a new one!");
+        storage()
+            .write_repository_config(installation_id, &data)
+            .expect("coudl not write RepositoryConfig");
+        info!("Repo config {installation_id} not found, will generate a new one!");
         config
     }
 
-    fn read_config_file(file_name: &str) -> Result<RepositoryConfig> {
-        let file_path = get_config_full_path_file(file_name);
-        let file_data = try_read_file(&file_path)?;
-        let app_config = serde_json::from_str::<RepositoryConfig>(file_data.as_str())?;
+    pub fn read(installation_id: u128) -> Result<RepositoryConfig> {
+        let Some(data) = storage().read_repository_config(installation_id)? else {
+            bail!(AppErrors::RepositoryConfigNotFound(installation_id));
+        };
+        let app_config = serde_json::from_str::<RepositoryConfig>(data.as_str())?;
 
         Ok(app_config)
     }
 
-    pub fn new(file_name: &str, app_config: &AppConfig) -> Result<RepositoryConfig> {
-        let Ok(result) = Self::read_config_file(file_name) else {
-            return Ok(Self::generate_default_config(file_name, app_config));
-        };
-        Ok(result)
+    // Only generated when there is none, an invalid config is reported instead of replaced
+    pub fn new(installation_id: u128, app_config: &AppConfig) -> Result<RepositoryConfig> {
+        if storage().read_repository_config(installation_id)?.is_none() {
+            return Ok(Self::generate_default_config(installation_id, app_config));
+        }
+        Self::read(installation_id)
     }
-}
 
-fn get_config_full_path_file(file: &str) -> PathBuf {
-    Path::new(CONFIG_DATA_PATH).join(file)
+    // Same checks as the startup validation, `source` names the config in the messages
+    pub fn problems(&self, source: &str) -> Vec<ConfigProblem> {
+        version_setting_problems(
+            source,
+            "file_to_donwload",
+            &self.file_to_donwload,
+            &self.pattern_version_to_search,
+            &self.branch_refs_to_observe,
+        )
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let problems = self.problems("repository config");
+        ensure!(
+            problems.is_empty(),
+            AppErrors::InvalidRepositoryConfig(
+                problems
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            )
+        );
+        Ok(())
+    }
+
+    pub fn save(&self, installation_id: u128) -> Result<()> {
+        let data = serde_json::to_string(self)?;
+        storage().write_repository_config(installation_id, &data)
+    }
+
+    // Returns false when there was no config to delete
+    pub fn delete(installation_id: u128) -> Result<bool> {
+        storage().delete_repository_config(installation_id)
+    }
 }
 
-fn try_read_file(file_loc: &PathBuf) -> Result<String> {
-    let data = fs::read_to_string(file_loc)?;
-    Ok(data)
+// Installations having a repository config
+pub fn list_installation_ids() -> Result<Vec<u128>> {
+    storage().list_installation_ids()
 }
 
 impl Default for AppConfig {
     fn default() -> Self {
         Self {
             callback_token: Default::default(),
+            previous_callback_tokens: Vec::new(),
             app_name: "IncreaseAppVersion".to_string(),
             private_signature: "sig.pem".to_string(),
+            private_key_file: None,
             app_id: Default::default(),
             whitelist_ips: Vec::new(),
+            deny_ips: Vec::new(),
+            trusted_proxies: Vec::new(),
+            proxy_protocol: false,
             commit_when_sender_is_bot: false,
             file_to_download: "version.hpp".to_string(),
             pattern_version_to_search: "#define VERSION".to_string(),
             branch_refs_to_observe: ["refs/heads/main".to_string()].to_vec(),
+            dry_run: false,
+            delivery_retention_days: default_delivery_retention_days(),
+            admin_token: None,
+            token_encryption_key: None,
+            hook_subnets_refresh_minutes: default_hook_subnets_refresh_minutes(),
+            token_refresh_margin_seconds: default_token_refresh_margin_seconds(),
+            local_repositories: Vec::new(),
+            tls: None,
+            signature_policy: SignaturePolicy::default(),
+            secret_provider: SecretProviderConfig::default(),
         }
     }
 }
 
 impl AppConfig {
+    // The current secret first, then the previous ones that did not expire yet
+    pub fn callback_secrets(&self) -> Vec<CallbackSecret> {
+        let current = CallbackSecret {
+            name: CURRENT_CALLBACK_SECRET.to_string(),
+            token: self.callback_token.clone(),
+            expires_at: None,
+        };
+        let now = Utc::now();
+        std::iter::once(current)
+            .chain(
+                self.previous_callback_tokens
+                    .iter()
+                    .filter(|secret| secret.expires_at.is_none_or(|expires_at| expires_at > now))
+                    .cloned(),
+            )
+            .collect()
+    }
+
     fn generate_default_config() -> AppConfig {
         let config = AppConfig::default();
         let data = serde_json::to_string(&config).expect("failed to convert AppConfig");
 
-        let file_path = get_config_full_path_file(CONFIG_FILE_APP);
-        fs::write(file_path, data).expect("coudl not write CONFIG_DATA_PATH");
+        storage()
+            .write_app_config(&data)
+            .expect("coudl not write AppConfig");
 
         config
     }
 
-    fn read_config_file() -> Result<AppConfig> {
-        let file_path = get_config_full_path_file(CONFIG_FILE_APP);
-        let file_data = try_read_file(&file_path)?;
-        let app_config = serde_json::from_str::<AppConfig>(file_data.as_str())?;
+    // Fields of the config file, the JSON config of the storage is created when missing
+    fn read_config_file(file: Option<&Path>) -> Result<Map<String, Value>> {
+        let value = match file {
+            Some(path) => parse_config_file(path)?,
+            // An unparsable config is reported, never replaced by the defaults
+            None => match storage().read_app_config()? {
+                Some(data) => match serde_json::from_str::<Value>(&data) {
+                    Ok(value) => value,
+                    Err(err) => bail!(AppErrors::InvalidConfigFile(
+                        config_file_name(None),
+                        err.to_string()
+                    )),
+                },
+                None => serde_json::to_value(Self::generate_default_config())?,
+            },
+        };
+        let Value::Object(fields) = value else {
+            bail!(AppErrors::InvalidConfigFile(
+                config_file_name(file),
+                "not a table of settings".to_string()
+            ));
+        };
+        Ok(fields)
+    }
 
-        Ok(app_config)
+    pub fn new(sources: &ConfigSources) -> Result<AppConfig> {
+        Self::from_sources(sources, |var| env::var(var).ok())
     }
 
-    pub fn new() -> Result<AppConfig> {
-        let Ok(mut result) = Self::read_config_file() else {
-            return Ok(Self::generate_default_config());
+    // `new` with the environment variables read through `lookup`
+    fn from_sources(
+        sources: &ConfigSources,
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<AppConfig> {
+        let Value::Object(mut fields) = serde_json::to_value(AppConfig::default())? else {
+            unreachable!("AppConfig serializes to an object");
+        };
+        fields.extend(Self::read_config_file(sources.file.as_deref())?);
+        let env_fields = read_vars_from_env(&fields, lookup)?;
+        fields.extend(env_fields);
+        fields.extend(sources.flags.clone());
+
+        let mut result = match serde_json::from_value::<AppConfig>(Value::Object(fields)) {
+            Ok(result) => result,
+            Err(err) => bail!(AppErrors::InvalidConfigFile(
+                config_file_name(sources.file.as_deref()),
+                err.to_string()
+            )),
         };
-        if let Err(err) = Self::read_vars_from_env(&mut result) {
-            info!("Failed to read env vars: {err}");
+        if let Some(private_key_file) = &result.private_key_file {
+            let Ok(signature) = fs::read_to_string(private_key_file) else {
+                bail!(AppErrors::InvalidEvironmentVariable(
+                    "private_key_file".to_string(),
+                    "could not read file"
+                ));
+            };
+            result.private_signature = signature;
         }
         Ok(result)
     }
 
-    fn read_vars_from_env(result: &mut AppConfig) -> Result<()> {
-        let mut missing_vars: Vec<&str> = Vec::with_capacity(EXPECTED_ENV_VARS.len());
-        for var in EXPECTED_ENV_VARS {
-            if let Ok(value) = env::var(var) {
-                match var {
-                    "CALLBACK_SECRET_TOKEN" => result.callback_token = value,
-                    "APP_NAME" => result.app_name = value,
-                    "COMMIT_WHEN_SENDER_IS_BOT" => {
-                        if let Ok(bool_value) = value.parse::<bool>() {
-                            result.commit_when_sender_is_bot = bool_value;
-                        } else {
-                            bail!(AppErrors::InvalidEvironmentVariable(
-                                var.to_string(),
-                                "invalid value"
-                            ));
-                        }
-                    }
-                    "PRIVATE_KEY_FILE_LOC" => {
-                        let Ok(signature) = try_read_file(&Path::new(&value).to_path_buf()) else {
-                            bail!(AppErrors::InvalidEvironmentVariable(
-                                var.to_string(),
-                                "could not read file"
-                            ));
-                        };
-                        result.private_signature = signature;
-                    }
-                    "APP_ID" => {
-                        if let Ok(value) = value.parse::<u128>() {
-                            result.app_id = value;
-                        } else {
-                            bail!(AppErrors::InvalidEvironmentVariable(
-                                var.to_string(),
-                                "invalid value, not unsinged number"
-                            ));
-                        }
-                    }
-                    "FILE_TO_DOWNLOAD" => result.file_to_download = value,
-                    "PATTERN_VERSION_TO_SEARCH" => result.pattern_version_to_search = value,
-                    _ => {}
-                }
-                continue;
+    // Effective config with the secrets that are set replaced by a placeholder
+    pub fn redacted(&self) -> Value {
+        let mut value = serde_json::to_value(self).expect("AppConfig serializes");
+        let secrets = [
+            ("callback_token", !self.callback_token.is_empty()),
+            ("private_signature", !self.private_signature.is_empty()),
+            ("admin_token", self.admin_token.is_some()),
+            ("token_encryption_key", self.token_encryption_key.is_some()),
+        ];
+        for (field, is_set) in secrets {
+            if is_set {
+                value[field] = Value::from(REDACTED);
+            }
+        }
+        value["previous_callback_tokens"] = self
+            .previous_callback_tokens
+            .iter()
+            .map(|secret| {
+                serde_json::json!({
+                    "name": secret.name,
+                    "token": REDACTED,
+                    "expires_at": secret.expires_at,
+                })
+            })
+            .collect();
+        value
+    }
+}
+
+// Problems of the file, pattern and refs a repository is bumped with, `file_field` is the name of
+// the file setting since repository configs keep its historical spelling
+fn version_setting_problems(
+    source: &str,
+    file_field: &'static str,
+    file_to_download: &str,
+    pattern_version_to_search: &str,
+    branch_refs_to_observe: &[String],
+) -> Vec<ConfigProblem> {
+    let mut problems = Vec::new();
+    if file_to_download.trim().is_empty() {
+        problems.push(ConfigProblem::EmptySetting(source.to_string(), file_field));
+    }
+    if pattern_version_to_search.trim().is_empty() {
+        problems.push(ConfigProblem::EmptySetting(
+            source.to_string(),
+            "pattern_version_to_search",
+        ));
+    }
+    if branch_refs_to_observe.is_empty() {
+        problems.push(ConfigProblem::EmptySetting(
+            source.to_string(),
+            "branch_refs_to_observe",
+        ));
+    }
+    for branch_ref in branch_refs_to_observe {
+        if !branch_ref.starts_with("refs/") {
+            problems.push(ConfigProblem::InvalidRef(
+                source.to_string(),
+                branch_ref.clone(),
+            ));
+        }
+    }
+    problems
+}
+
+impl AppConfig {
+    // Everything a delivery would otherwise fail on, without the stored repository configs
+    pub fn problems(&self) -> Vec<ConfigProblem> {
+        let mut problems = Vec::new();
+        if self.app_id == 0 {
+            problems.push(ConfigProblem::MissingAppId);
+        }
+        if self.callback_token.is_empty() {
+            problems.push(ConfigProblem::MissingSecret("callback_token".to_string()));
+        }
+        for secret in &self.previous_callback_tokens {
+            if secret.token.is_empty() {
+                problems.push(ConfigProblem::MissingSecret(format!(
+                    "previous_callback_tokens.{}",
+                    secret.name
+                )));
+            }
+        }
+        if self.private_signature.is_empty() {
+            problems.push(ConfigProblem::MissingSecret("private_key".to_string()));
+        } else if let Err(err) = EncodingKey::from_rsa_pem(self.private_signature.as_bytes()) {
+            problems.push(ConfigProblem::InvalidPrivateKey(err.to_string()));
+        }
+        problems.extend(version_setting_problems(
+            "app config",
+            "file_to_download",
+            &self.file_to_download,
+            &self.pattern_version_to_search,
+            &self.branch_refs_to_observe,
+        ));
+        for local_repository in &self.local_repositories {
+            let source = format!("local repository `{}`", local_repository.name);
+            if local_repository.remote_url.trim().is_empty() {
+                problems.push(ConfigProblem::EmptySetting(source.clone(), "remote_url"));
             }
-            missing_vars.push(var);
+            problems.extend(version_setting_problems(
+                &source,
+                "file_to_download",
+                &local_repository.file_to_download,
+                &local_repository.pattern_version_to_search,
+                &local_repository.branch_refs_to_observe,
+            ));
         }
-        if !missing_vars.is_empty() {
-            let missing_vars_joined = missing_vars.join(", ");
-            bail!(AppErrors::MissingEvironmentVariables(missing_vars_joined));
+        let ip_lists = [
+            ("whitelist_ips", &self.whitelist_ips),
+            ("deny_ips", &self.deny_ips),
+            ("trusted_proxies", &self.trusted_proxies),
+        ];
+        for (field, entries) in ip_lists {
+            if let Err(err) = SecurityConfig::from_entries(entries) {
+                let entries = match err.downcast_ref::<AppErrors>() {
+                    Some(AppErrors::InvalidIpEntries(entries)) => entries.clone(),
+                    _ => err.to_string(),
+                };
+                problems.push(ConfigProblem::InvalidIpEntries(field, entries));
+            }
         }
+        problems
+    }
 
+    // Run at startup, reports the problems of the app config and of every stored repository config
+    // at once
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = self.problems();
+        for installation_id in list_installation_ids()? {
+            match RepositoryConfig::read(installation_id) {
+                Ok(config) => problems.extend(config.problems(&format!(
+                    "repository config of installation {installation_id}"
+                ))),
+                Err(err) => problems.push(ConfigProblem::InvalidRepositoryConfig(
+                    installation_id,
+                    err.to_string(),
+                )),
+            }
+        }
+        ensure!(problems.is_empty(), AppErrors::InvalidConfig(problems));
         Ok(())
     }
 }
 
+fn config_file_name(file: Option<&Path>) -> String {
+    match file {
+        Some(path) => path.display().to_string(),
+        None => "app config".to_string(),
+    }
+}
+
+// The format is picked from the extension
+fn parse_config_file(path: &Path) -> Result<Value> {
+    let invalid = |reason: String| AppErrors::InvalidConfigFile(path.display().to_string(), reason);
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(err) => bail!(invalid(err.to_string())),
+    };
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default();
+    let value = match extension {
+        "toml" => toml::from_str::<Value>(&data).map_err(|err| invalid(err.to_string()))?,
+        "yaml" | "yml" => {
+            serde_yaml::from_str::<Value>(&data).map_err(|err| invalid(err.to_string()))?
+        }
+        "json" => serde_json::from_str::<Value>(&data).map_err(|err| invalid(err.to_string()))?,
+        _ => bail!(invalid(
+            "expected a .toml, .yaml, .yml or .json file".to_string()
+        )),
+    };
+    Ok(value)
+}
+
+// The value of an environment variable, typed like the field it overrides
+fn env_value(var: &str, current: Option<&Value>, value: String) -> Result<Value> {
+    let invalid = || AppErrors::InvalidEvironmentVariable(var.to_string(), "invalid value");
+    let value = match current {
+        Some(Value::Array(_)) => Value::from(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .collect::<Vec<&str>>(),
+        ),
+        Some(Value::Bool(_)) => Value::from(value.trim().parse::<bool>().map_err(|_| invalid())?),
+        Some(Value::Number(_)) => Value::from(value.trim().parse::<u64>().map_err(|_| invalid())?),
+        _ => Value::from(value),
+    };
+    Ok(value)
+}
+
+fn read_vars_from_env(
+    fields: &Map<String, Value>,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<Map<String, Value>> {
+    let mut env_fields = Map::new();
+    for (var, field) in ENV_OVERRIDES {
+        if let Some(value) = lookup_or_file(var, &lookup)? {
+            env_fields.insert(field.to_string(), env_value(var, fields.get(field), value)?);
+        }
+    }
+    Ok(env_fields)
+}
+
+#[derive(Debug)]
 pub struct SecurityConfig {
     pub subnets: Vec<IpNet>,
 }
 
 impl SecurityConfig {
+    // Accepts CIDR ranges and single addresses, every invalid entry is reported at once
+    pub fn from_entries(entries: &[String]) -> Result<SecurityConfig> {
+        let mut subnets: Vec<IpNet> = Vec::with_capacity(entries.len());
+        let mut invalid_entries: Vec<&str> = Vec::new();
+        for entry in entries {
+            let entry = entry.trim();
+            if let Ok(subnet) = entry.parse::<IpNet>() {
+                subnets.push(subnet);
+            } else if let Ok(ip) = entry.parse::<IpAddr>() {
+                subnets.push(IpNet::from(ip.to_canonical()));
+            } else {
+                invalid_entries.push(entry);
+            }
+        }
+        ensure!(
+            invalid_entries.is_empty(),
+            AppErrors::InvalidIpEntries(invalid_entries.join(", "))
+        );
+        Ok(SecurityConfig { subnets })
+    }
+
+    // IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) are matched as their IPv4 form
     pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
         for subnet in &self.subnets {
             if subnet.contains(&ip) {
                 return true;
@@ -207,3 +625,239 @@ impl SecurityConfig {
         false
     }
 }
+
+// Allow and deny lists from the config, the deny list wins over every allowed range
+pub struct IpFilter {
+    pub allowed: SecurityConfig,
+    pub denied: SecurityConfig,
+    pub trusted_proxies: SecurityConfig,
+}
+
+impl IpFilter {
+    pub fn new(app_config: &AppConfig) -> Result<IpFilter> {
+        Ok(IpFilter {
+            allowed: SecurityConfig::from_entries(&app_config.whitelist_ips)?,
+            denied: SecurityConfig::from_entries(&app_config.deny_ips)?,
+            trusted_proxies: SecurityConfig::from_entries(&app_config.trusted_proxies)?,
+        })
+    }
+
+    pub fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.contains(ip)
+    }
+
+    // Forwarding headers are only read when the connection comes from a trusted proxy
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        resolve_client_ip(peer, headers, &self.trusted_proxies)
+    }
+
+    pub fn is_denied(&self, ip: IpAddr) -> bool {
+        self.denied.contains(ip)
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        !self.is_denied(ip) && self.allowed.contains(ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tempfile::TempDir;
+
+    #[test]
+    fn layers_file_env_and_flags() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("config.toml");
+        fs::write(
+            &file,
+            r#"
+app_id = 1
+app_name = "from file"
+whitelist_ips = ["10.0.0.0/8"]
+branch_refs_to_observe = ["refs/heads/main"]
+delivery_retention_days = 30
+
+[tls]
+cert_path = "cert.pem"
+key_path = "key.pem"
+"#,
+        )
+        .unwrap();
+        let sources = ConfigSources {
+            file: Some(file),
+            flags: serde_json::json!({ "app_id": 7 })
+                .as_object()
+                .unwrap()
+                .clone(),
+        };
+        let env = HashMap::from([
+            ("APP_ID", "3"),
+            ("APP_NAME", "from env"),
+            ("HOOK_SUBNETS_REFRESH_MINUTES", "5"),
+        ]);
+        let app_config =
+            AppConfig::from_sources(&sources, |var| env.get(var).map(|value| value.to_string()))
+                .unwrap();
+
+        assert_eq!(app_config.app_id, 7);
+        assert_eq!(app_config.app_name, "from env");
+        assert_eq!(app_config.whitelist_ips, vec!["10.0.0.0/8"]);
+        assert_eq!(app_config.delivery_retention_days, 30);
+        assert_eq!(app_config.hook_subnets_refresh_minutes, 5);
+        assert_eq!(app_config.file_to_download, "version.hpp");
+        assert!(app_config.tls.is_some());
+    }
+
+    #[test]
+    fn types_env_values_like_their_field() {
+        let defaults = serde_json::to_value(AppConfig::default()).unwrap();
+        let value = |field: &str, raw: &str| env_value("VAR", defaults.get(field), raw.to_string());
+        assert_eq!(
+            value("branch_refs_to_observe", "refs/heads/main, refs/heads/dev,").unwrap(),
+            serde_json::json!(["refs/heads/main", "refs/heads/dev"])
+        );
+        assert_eq!(value("dry_run", "true").unwrap(), Value::Bool(true));
+        assert_eq!(value("app_id", "42").unwrap(), Value::from(42));
+        assert!(value("app_id", "forty-two").is_err());
+        assert_eq!(value("app_name", "bot").unwrap(), Value::from("bot"));
+    }
+
+    #[test]
+    fn reads_yaml_and_rejects_unknown_formats() {
+        let dir = TempDir::new().unwrap();
+        let yaml = dir.path().join("config.yaml");
+        fs::write(&yaml, "app_id: 3\nwhitelist_ips:\n  - 192.0.2.1\n").unwrap();
+        assert_eq!(
+            parse_config_file(&yaml).unwrap(),
+            serde_json::json!({ "app_id": 3, "whitelist_ips": ["192.0.2.1"] })
+        );
+        let ini = dir.path().join("config.ini");
+        fs::write(&ini, "app_id=3").unwrap();
+        assert!(parse_config_file(&ini).is_err());
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let app_config = AppConfig {
+            app_id: 0,
+            private_signature: "not a key".to_string(),
+            previous_callback_tokens: vec![CallbackSecret {
+                name: "2025".to_string(),
+                token: String::new(),
+                expires_at: None,
+            }],
+            pattern_version_to_search: " ".to_string(),
+            branch_refs_to_observe: vec!["main".to_string()],
+            whitelist_ips: vec!["10.0.0.0/8".to_string(), "nope".to_string()],
+            deny_ips: vec!["999.1.1.1".to_string()],
+            ..AppConfig::default()
+        };
+        let problems: Vec<String> = app_config
+            .problems()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            problems,
+            vec![
+                "app_id is not set",
+                "secret `callback_token` is not set",
+                "secret `previous_callback_tokens.2025` is not set",
+                "the private key is not a valid RSA PEM key: InvalidKeyFormat",
+                "app config: `pattern_version_to_search` is empty",
+                "app config: ref `main` does not start with refs/",
+                "`whitelist_ips`: invalid IP address or range: nope",
+                "`deny_ips`: invalid IP address or range: 999.1.1.1",
+            ]
+        );
+        let err = AppErrors::InvalidConfig(app_config.problems()).to_string();
+        assert!(err.starts_with("Invalid config:\n  - app_id is not set\n"));
+    }
+
+    #[test]
+    fn checks_repository_configs_like_the_app_config() {
+        let config = RepositoryConfig {
+            commit_when_sender_is_bot: false,
+            file_to_donwload: String::new(),
+            pattern_version_to_search: "version = ".to_string(),
+            branch_refs_to_observe: vec!["main".to_string()],
+            dry_run: false,
+        };
+        let problems: Vec<String> = config
+            .problems("repository config of installation 7")
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            problems,
+            vec![
+                "repository config of installation 7: `file_to_donwload` is empty",
+                "repository config of installation 7: ref `main` does not start with refs/",
+            ]
+        );
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "Invalid repository config: repository config: `file_to_donwload` is empty, \
+             repository config: ref `main` does not start with refs/"
+        );
+    }
+
+    #[test]
+    fn redacts_secrets() {
+        let app_config = AppConfig {
+            callback_token: "callback secret".to_string(),
+            previous_callback_tokens: vec![CallbackSecret {
+                name: "2025".to_string(),
+                token: "old secret".to_string(),
+                expires_at: None,
+            }],
+            admin_token: Some("admin secret".to_string()),
+            ..AppConfig::default()
+        };
+        let shown = app_config.redacted();
+        let data = shown.to_string();
+        for secret in ["callback secret", "old secret", "admin secret"] {
+            assert!(!data.contains(secret));
+        }
+        assert_eq!(shown["callback_token"], REDACTED);
+        assert_eq!(shown["previous_callback_tokens"][0]["name"], "2025");
+        assert!(shown["token_encryption_key"].is_null());
+    }
+
+    #[test]
+    fn parses_addresses_and_ranges() {
+        let entries = ["10.0.0.0/8".to_string(), " 2001:db8::1 ".to_string()];
+        let security = SecurityConfig::from_entries(&entries).unwrap();
+        assert!(security.contains("10.1.2.3".parse().unwrap()));
+        assert!(security.contains("2001:db8::1".parse().unwrap()));
+        assert!(!security.contains("2001:db8::2".parse().unwrap()));
+        assert!(!security.contains("11.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn reports_every_invalid_entry() {
+        let entries = [
+            "10.0.0.1".to_string(),
+            "10.0.0.0/33".to_string(),
+            "localhost".to_string(),
+        ];
+        let err = SecurityConfig::from_entries(&entries).unwrap_err();
+        assert!(err.to_string().contains("10.0.0.0/33, localhost"));
+    }
+
+    #[test]
+    fn matches_mapped_addresses_and_applies_deny_list() {
+        let app_config = AppConfig {
+            whitelist_ips: vec!["192.168.0.0/16".to_string(), "::ffff:10.0.0.1".to_string()],
+            deny_ips: vec!["192.168.1.0/24".to_string()],
+            ..AppConfig::default()
+        };
+        let filter = IpFilter::new(&app_config).unwrap();
+        assert!(filter.is_allowed("::ffff:192.168.0.7".parse().unwrap()));
+        assert!(filter.is_allowed("10.0.0.1".parse().unwrap()));
+        assert!(!filter.is_allowed("192.168.1.7".parse().unwrap()));
+        assert!(filter.is_denied("::ffff:192.168.1.7".parse().unwrap()));
+    }
+}
@@ -2,8 +2,6 @@ use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum AppErrors<'a> {
-    #[error("Missing enviroment variables: {0}")]
-    MissingEvironmentVariables(String),
     #[error("Failed parsing variable `{0}` with reason: {1}")]
     InvalidEvironmentVariable(String, &'a str),
     #[error("Too many query params: `{0}`, expected 0")]
@@ -30,4 +28,87 @@ pub enum AppErrors<'a> {
     FailedToDecodeFile(&'a str, String),
     #[error("Failed increase version in file: {0}")]
     FailedToIncreaseVersionInFile(String),
+    #[error("Unknown local repository: `{0}`")]
+    UnknownLocalRepository(String),
+    #[error("Git command failed: {0}")]
+    GitCommandFailed(String),
+    #[error("Stored delivery not found: `{0}`")]
+    DeliveryNotFound(String),
+    #[error("Invalid repository config: {0}")]
+    InvalidRepositoryConfig(String),
+    #[error("Repository config not found for installation {0}")]
+    RepositoryConfigNotFound(u128),
+    #[error("Invalid config file `{0}`: {1}")]
+    InvalidConfigFile(String, String),
+    #[error("Invalid config:\n{}", list_problems(.0))]
+    InvalidConfig(Vec<ConfigProblem>),
+    #[error("Invalid IP address or range: {0}")]
+    InvalidIpEntries(String),
+    #[error("Could not read secret: {0}")]
+    SecretUnavailable(String),
+    #[error("Failed to encrypt or decrypt installation file: {0}")]
+    TokenEncryptionFailed(String),
+    #[error("`{0}` already holds audit entries, pass --force to append to them")]
+    ImportTargetNotEmpty(String),
+}
+
+impl AppErrors<'_> {
+    // Stable name used as metric label
+    pub fn variant_name(&self) -> &'static str {
+        match self {
+            AppErrors::InvalidEvironmentVariable(..) => "invalid_environment_variable",
+            AppErrors::TooManyQueryParams(..) => "too_many_query_params",
+            AppErrors::MissingHeader(..) => "missing_header",
+            AppErrors::HeaderInvalidFormatError(..) => "header_invalid_format_error",
+            AppErrors::HeaderParsingError(..) => "header_parsing_error",
+            AppErrors::SignatureError(..) => "signature_mismatch",
+            AppErrors::InvalidPayload(..) => "invalid_payload",
+            AppErrors::InvalidDeserializationInstallationFile(..) => {
+                "invalid_deserialization_installation_file"
+            }
+            AppErrors::FailedToSaveInstallationFile(..) => "failed_to_save_installation_file",
+            AppErrors::FailedToProcessJWD(..) => "failed_to_process_jwt",
+            AppErrors::ApiFailure(..) => "api_failure",
+            AppErrors::FailedToDecodeFile(..) => "failed_to_decode_file",
+            AppErrors::FailedToIncreaseVersionInFile(..) => "failed_to_increase_version_in_file",
+            AppErrors::UnknownLocalRepository(..) => "unknown_local_repository",
+            AppErrors::GitCommandFailed(..) => "git_command_failed",
+            AppErrors::DeliveryNotFound(..) => "delivery_not_found",
+            AppErrors::InvalidRepositoryConfig(..) => "invalid_repository_config",
+            AppErrors::RepositoryConfigNotFound(..) => "repository_config_not_found",
+            AppErrors::InvalidConfigFile(..) => "invalid_config_file",
+            AppErrors::InvalidConfig(..) => "invalid_config",
+            AppErrors::InvalidIpEntries(..) => "invalid_ip_entries",
+            AppErrors::SecretUnavailable(..) => "secret_unavailable",
+            AppErrors::TokenEncryptionFailed(..) => "token_encryption_failed",
+            AppErrors::ImportTargetNotEmpty(..) => "import_target_not_empty",
+        }
+    }
+}
+
+// One problem found by the startup validation, `AppErrors::InvalidConfig` reports all of them
+#[derive(Error, Debug)]
+pub enum ConfigProblem {
+    #[error("app_id is not set")]
+    MissingAppId,
+    #[error("secret `{0}` is not set")]
+    MissingSecret(String),
+    #[error("the private key is not a valid RSA PEM key: {0}")]
+    InvalidPrivateKey(String),
+    #[error("{0}: `{1}` is empty")]
+    EmptySetting(String, &'static str),
+    #[error("{0}: ref `{1}` does not start with refs/")]
+    InvalidRef(String, String),
+    #[error("`{0}`: invalid IP address or range: {1}")]
+    InvalidIpEntries(&'static str, String),
+    #[error("repository config of installation {0}: {1}")]
+    InvalidRepositoryConfig(u128, String),
+}
+
+fn list_problems(problems: &[ConfigProblem]) -> String {
+    problems
+        .iter()
+        .map(|problem| format!("  - {problem}"))
+        .collect::<Vec<String>>()
+        .join("\n")
 }
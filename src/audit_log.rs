@@ -0,0 +1,200 @@
+use anyhow::Result;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    storage::{storage, Storage},
+    webhook_data::{LocalPushHook, WebWebHook},
+    worker::DeliveryOutcome,
+};
+
+// One line of the append-only audit log, written for every delivery that passed validation
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: String,
+    pub delivery_id: Option<String>,
+    pub repository: String,
+    #[serde(rename = "ref")]
+    pub ref_: String,
+    pub pusher: Option<String>,
+    pub commits: Vec<String>,
+    pub old_version: Option<String>,
+    pub new_version: Option<String>,
+    pub commit_sha: Option<String>,
+    pub dry_run: bool,
+    pub replay: bool,
+    pub outcome: String,
+    pub error: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+pub struct AuditFilter {
+    pub repository: Option<String>,
+    pub limit: Option<usize>,
+}
+
+impl AuditEntry {
+    fn new(repository: String, ref_: String) -> AuditEntry {
+        AuditEntry {
+            timestamp: Utc::now().to_rfc3339(),
+            delivery_id: None,
+            repository,
+            ref_,
+            pusher: None,
+            commits: Vec::new(),
+            old_version: None,
+            new_version: None,
+            commit_sha: None,
+            dry_run: false,
+            replay: false,
+            outcome: String::new(),
+            error: None,
+        }
+    }
+
+    pub fn from_webhook(delivery_id: Option<String>, webhook: &WebWebHook) -> AuditEntry {
+        let mut entry = Self::new(webhook.repository.full_name.clone(), webhook.ref_.clone());
+        entry.delivery_id = delivery_id;
+        entry.pusher = Some(webhook.pusher.name.clone());
+        entry.commits = webhook
+            .commits
+            .iter()
+            .map(|commit| commit.id.clone())
+            .collect();
+        entry
+    }
+
+    pub fn from_local_hook(hook: &LocalPushHook) -> AuditEntry {
+        let mut entry = Self::new(hook.repository.clone(), hook.ref_.clone());
+        entry.commits = vec![hook.after.clone()];
+        entry
+    }
+
+    pub fn record_result(&mut self, result: &Result<DeliveryOutcome>) {
+        match result {
+            Ok(outcome) => {
+                self.outcome = outcome.metric_label().to_string();
+                if let DeliveryOutcome::Bumped(report) = outcome {
+                    self.old_version = Some(report.old_version.clone());
+                    self.new_version = Some(report.new_version.clone());
+                    self.commit_sha = report.commit_sha.clone();
+                    self.dry_run = report.dry_run;
+                }
+            }
+            Err(err) => {
+                self.outcome = "failed".to_string();
+                self.error = Some(err.to_string());
+            }
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let version = match (&self.old_version, &self.new_version) {
+            (Some(old), Some(new)) => format!("{old} -> {new}"),
+            _ => "-".to_string(),
+        };
+        format!(
+            "{} {} {} {} {} {}{}",
+            self.timestamp,
+            self.repository,
+            self.ref_,
+            version,
+            self.outcome,
+            self.commit_sha.as_deref().unwrap_or("-"),
+            self.error
+                .as_ref()
+                .map(|err| format!(" ({err})"))
+                .unwrap_or_default()
+        )
+    }
+}
+
+pub fn append_audit_entry(entry: &AuditEntry) -> Result<()> {
+    let data = serde_json::to_string(entry)?;
+    storage().append_audit_entry(&data)
+}
+
+// Most recent entries first
+pub fn read_audit_entries(filter: &AuditFilter) -> Result<Vec<AuditEntry>> {
+    read_entries(storage(), filter)
+}
+
+fn read_entries(storage: &dyn Storage, filter: &AuditFilter) -> Result<Vec<AuditEntry>> {
+    let mut entries: Vec<AuditEntry> = storage
+        .read_audit_entries()?
+        .iter()
+        .filter_map(|line| match serde_json::from_str::<AuditEntry>(line) {
+            Ok(entry) => Some(entry),
+            Err(err) => {
+                warn!("Skipping invalid audit entry: {err}");
+                None
+            }
+        })
+        .filter(|entry| {
+            filter
+                .repository
+                .as_ref()
+                .is_none_or(|repository| &entry.repository == repository)
+        })
+        .collect();
+    entries.reverse();
+    if let Some(limit) = filter.limit {
+        entries.truncate(limit);
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::storage::FileStorage;
+
+    fn append(storage: &dyn Storage, repository: &str, commit: &str) {
+        let mut entry = AuditEntry::new(repository.to_string(), "refs/heads/main".to_string());
+        entry.commits = vec![commit.to_string()];
+        storage
+            .append_audit_entry(&serde_json::to_string(&entry).unwrap())
+            .unwrap();
+    }
+
+    fn commits(entries: &[AuditEntry]) -> Vec<&str> {
+        entries
+            .iter()
+            .map(|entry| entry.commits[0].as_str())
+            .collect()
+    }
+
+    #[test]
+    fn reads_most_recent_first_skipping_corrupt_lines() {
+        let dir = TempDir::new().unwrap();
+        let storage = FileStorage::new(dir.path());
+        storage.prepare().unwrap();
+        append(&storage, "octo/app", "a1");
+        append(&storage, "octo/lib", "b1");
+        storage.append_audit_entry("{\"truncated\":").unwrap();
+        append(&storage, "octo/app", "a2");
+        append(&storage, "octo/app", "a3");
+
+        let all = read_entries(&storage, &AuditFilter::default()).unwrap();
+        assert_eq!(commits(&all), vec!["a3", "a2", "b1", "a1"]);
+
+        let filter = AuditFilter {
+            repository: Some("octo/app".to_string()),
+            limit: Some(2),
+        };
+        let app_entries = read_entries(&storage, &filter).unwrap();
+        assert_eq!(commits(&app_entries), vec!["a3", "a2"]);
+
+        let filter = AuditFilter {
+            repository: Some("octo/lib".to_string()),
+            limit: None,
+        };
+        assert_eq!(
+            commits(&read_entries(&storage, &filter).unwrap()),
+            vec!["b1"]
+        );
+    }
+}
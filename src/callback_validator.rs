@@ -1,105 +1,171 @@
-use crate::app_config::AppConfig;
-use crate::{app_errors::AppErrors, webhook_data::WebWebHook};
+use crate::app_config::{AppConfig, CallbackSecret, SignaturePolicy};
+use crate::{
+    app_errors::AppErrors,
+    metrics::SECRET_MATCHES,
+    webhook_data::{LocalPushHook, WebWebHook},
+};
 use anyhow::{bail, ensure, Result};
 use axum::{body::Bytes, http::HeaderMap};
 use hmac::{Hmac, Mac};
+use sha1::Sha1;
 use sha2::Sha256;
 use std::collections::HashMap;
+use tracing::{debug, instrument, warn};
 
 type HmacSha256 = Hmac<Sha256>;
+type HmacSha1 = Hmac<Sha1>;
 
-static EXPECTED_CALLBACK_HEADERS: [&str; 8] = [
+static REQUIRED_CALLBACK_HEADERS: [&str; 2] = ["X-GitHub-Event", "X-GitHub-Delivery"];
+
+// Not sent with every delivery (pings, redeliveries, older GitHub Enterprise), checked when present
+static OPTIONAL_CALLBACK_HEADERS: [&str; 4] = [
     "X-GitHub-Hook-ID",
-    "X-GitHub-Event",
-    "X-GitHub-Delivery",
-    "X-Hub-Signature",
-    "X-Hub-Signature-256",
     "User-Agent",
     "X-GitHub-Hook-Installation-Target-Type",
     "X-GitHub-Hook-Installation-Target-ID",
 ];
 
-async fn validate_headers_and_get_signature_256(headers: &HeaderMap) -> Result<String> {
-    let mut signature_256: String = String::new();
-    for header in EXPECTED_CALLBACK_HEADERS {
-        let entry = headers.get(header);
-        let Some(entry) = entry else {
-            bail!(AppErrors::MissingHeader(header));
-        };
+static SIGNATURE_256_HEADER: &str = "X-Hub-Signature-256";
+static SIGNATURE_1_HEADER: &str = "X-Hub-Signature";
 
-        let Ok(val) = entry.to_str() else {
-            bail!(AppErrors::HeaderInvalidFormatError(header));
-        };
+enum Signature {
+    Sha256(Vec<u8>),
+    Sha1(Vec<u8>),
+}
+
+fn header_value<'a>(headers: &'a HeaderMap, header: &'static str) -> Result<Option<&'a str>> {
+    let Some(entry) = headers.get(header) else {
+        return Ok(None);
+    };
+    let Ok(val) = entry.to_str() else {
+        bail!(AppErrors::HeaderInvalidFormatError(header));
+    };
+    Ok(Some(val))
+}
 
-        match header {
-            "X-Hub-Signature-256" => {
-                ensure!(
-                    val.starts_with("sha256="),
-                    AppErrors::HeaderParsingError(header)
-                );
-                signature_256 = val.to_string();
+fn validate_headers(headers: &HeaderMap) -> Result<()> {
+    for header in REQUIRED_CALLBACK_HEADERS {
+        ensure!(
+            header_value(headers, header)?.is_some(),
+            AppErrors::MissingHeader(header)
+        );
+    }
+    for header in OPTIONAL_CALLBACK_HEADERS {
+        let Some(val) = header_value(headers, header)? else {
+            continue;
+        };
+        let valid = match header {
+            "User-Agent" => val.starts_with("GitHub-Hookshot/"),
+            "X-GitHub-Hook-ID" | "X-GitHub-Hook-Installation-Target-ID" => {
+                val.parse::<u64>().is_ok()
             }
-            "X-Hub-Signature" => ensure!(
-                val.starts_with("sha1="),
-                AppErrors::HeaderParsingError(header)
-            ),
-            "User-Agent" => ensure!(
-                val.starts_with("GitHub-Hookshot/"),
-                AppErrors::HeaderParsingError(header)
-            ),
-            "X-GitHub-Hook-ID" => ensure!(
-                val.parse::<u32>().is_ok(),
-                AppErrors::HeaderParsingError(header)
-            ),
-            "X-GitHub-Hook-Installation-Target-ID" => ensure!(
-                val.parse::<u32>().is_ok(),
-                AppErrors::HeaderParsingError(header)
-            ),
-            _ => {}
-        }
+            _ => true,
+        };
+        ensure!(valid, AppErrors::HeaderParsingError(header));
     }
-    Ok(signature_256)
+    Ok(())
 }
 
-async fn verify_signature(
-    payload_body: &Bytes,
-    signature: &str,
-    secret_token: &String,
-) -> Result<()> {
-    ensure!(
-        signature.len() >= 10,
-        AppErrors::HeaderParsingError("X-Hub-Signature-256")
-    );
-
-    let signature_chracters = &signature[7..];
-    let signature_size = signature_chracters.len();
-    ensure!(
-        signature_size % 2 == 0,
-        AppErrors::SignatureError("Invalid header size")
-    );
-
-    let expected_signature = hex::decode(signature_chracters);
-    let Ok(expected_signature) = expected_signature else {
+fn parse_signature(
+    headers: &HeaderMap,
+    header: &'static str,
+    prefix: &str,
+) -> Result<Option<Vec<u8>>> {
+    let Some(val) = header_value(headers, header)? else {
+        return Ok(None);
+    };
+    let Some(signature_chracters) = val.strip_prefix(prefix) else {
+        bail!(AppErrors::HeaderParsingError(header));
+    };
+    let Ok(signature) = hex::decode(signature_chracters) else {
         bail!(AppErrors::SignatureError("Invalid expected signature"));
     };
+    Ok(Some(signature))
+}
 
-    let hash_obj = HmacSha256::new_from_slice(secret_token.as_bytes());
-
-    let Ok(mut hash_obj) = hash_obj else {
-        bail!(AppErrors::SignatureError("Invalid hash obj"));
+// Signatures to verify for the configured policy, the SHA-256 one is always preferred
+fn signatures_to_verify(headers: &HeaderMap, policy: SignaturePolicy) -> Result<Vec<Signature>> {
+    let sha256 = parse_signature(headers, SIGNATURE_256_HEADER, "sha256=")?;
+    let sha1 = match policy {
+        SignaturePolicy::Sha256 => None,
+        SignaturePolicy::Sha256OrSha1 | SignaturePolicy::Both => {
+            parse_signature(headers, SIGNATURE_1_HEADER, "sha1=")?
+        }
     };
 
-    hash_obj.update(payload_body);
+    match (policy, sha256, sha1) {
+        (SignaturePolicy::Both, Some(sha256), Some(sha1)) => {
+            Ok(vec![Signature::Sha256(sha256), Signature::Sha1(sha1)])
+        }
+        (SignaturePolicy::Both, None, _) => bail!(AppErrors::MissingHeader(SIGNATURE_256_HEADER)),
+        (SignaturePolicy::Both, _, None) => bail!(AppErrors::MissingHeader(SIGNATURE_1_HEADER)),
+        (_, Some(sha256), _) => Ok(vec![Signature::Sha256(sha256)]),
+        (SignaturePolicy::Sha256OrSha1, None, Some(sha1)) => Ok(vec![Signature::Sha1(sha1)]),
+        _ => bail!(AppErrors::MissingHeader(SIGNATURE_256_HEADER)),
+    }
+}
 
-    let result = hash_obj.finalize().into_bytes();
-    let result = result.as_slice();
+// `verify_slice` compares in constant time
+fn verify_signature(payload_body: &Bytes, signature: &Signature, secret_token: &str) -> Result<()> {
+    let verified = match signature {
+        Signature::Sha256(expected) => {
+            let Ok(mut hash_obj) = HmacSha256::new_from_slice(secret_token.as_bytes()) else {
+                bail!(AppErrors::SignatureError("Invalid hash obj"));
+            };
+            hash_obj.update(payload_body);
+            hash_obj.verify_slice(expected).is_ok()
+        }
+        Signature::Sha1(expected) => {
+            let Ok(mut hash_obj) = HmacSha1::new_from_slice(secret_token.as_bytes()) else {
+                bail!(AppErrors::SignatureError("Invalid hash obj"));
+            };
+            hash_obj.update(payload_body);
+            hash_obj.verify_slice(expected).is_ok()
+        }
+    };
     ensure!(
-        result == expected_signature,
+        verified,
         AppErrors::SignatureError("Signatures do not match")
     );
     Ok(())
 }
 
+// Tries the secrets in order, a delivery is accepted when one of them verifies every signature
+fn verify_with_secrets<'a>(
+    payload_body: &Bytes,
+    signatures: &[Signature],
+    secrets: &'a [CallbackSecret],
+) -> Result<(usize, &'a CallbackSecret)> {
+    for (position, secret) in secrets.iter().enumerate() {
+        if signatures
+            .iter()
+            .all(|signature| verify_signature(payload_body, signature, &secret.token).is_ok())
+        {
+            return Ok((position, secret));
+        }
+    }
+    bail!(AppErrors::SignatureError("Signatures do not match"))
+}
+
+fn verify_delivery(
+    app_config: &AppConfig,
+    payload_body: &Bytes,
+    signatures: &[Signature],
+) -> Result<()> {
+    let secrets = app_config.callback_secrets();
+    let (position, secret) = verify_with_secrets(payload_body, signatures, &secrets)?;
+    SECRET_MATCHES.with_label_values(&[&secret.name]).inc();
+    match position {
+        0 => debug!("Signature verified with the {} secret", secret.name),
+        _ => warn!(
+            "Signature verified with the deprecated secret {}, update the webhook secret",
+            secret.name
+        ),
+    }
+    Ok(())
+}
+
+#[instrument(skip_all)]
 pub async fn callback_validator(
     app_config: &AppConfig,
     query_params: HashMap<String, String>,
@@ -110,13 +176,9 @@ pub async fn callback_validator(
         query_params.is_empty(),
         AppErrors::TooManyQueryParams(query_params.len())
     );
-    let signature_header = validate_headers_and_get_signature_256(&headers).await?;
-    verify_signature(
-        &payload,
-        signature_header.as_str(),
-        &app_config.callback_token,
-    )
-    .await?;
+    validate_headers(&headers)?;
+    let signatures = signatures_to_verify(&headers, app_config.signature_policy)?;
+    verify_delivery(app_config, &payload, &signatures)?;
 
     let Ok(webhook) = serde_json::from_slice(&payload) else {
         bail!(AppErrors::InvalidPayload());
@@ -124,3 +186,140 @@ pub async fn callback_validator(
 
     Ok(webhook)
 }
+
+// Generic signed webhook used by plain git servers, only the HMAC signature header is required
+#[instrument(skip_all)]
+pub async fn local_callback_validator(
+    app_config: &AppConfig,
+    headers: HeaderMap,
+    payload: Bytes,
+) -> Result<LocalPushHook> {
+    let signatures = signatures_to_verify(&headers, SignaturePolicy::Sha256)?;
+    verify_delivery(app_config, &payload, &signatures)?;
+
+    let Ok(hook) = serde_json::from_slice(&payload) else {
+        bail!(AppErrors::InvalidPayload());
+    };
+
+    Ok(hook)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, Utc};
+
+    static SECRET: &str = "It's a Secret to Everybody";
+    static PAYLOAD: &str = "Hello, World!";
+    // Signatures of PAYLOAD with SECRET, the SHA-256 one is the example of the GitHub docs
+    static SHA256: &str = "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+    static SHA1: &str = "sha1=01dc10d0c83e72ed246219cdd91669667fe2ca59";
+
+    fn headers(recorded: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in recorded {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn push_delivery() -> HeaderMap {
+        headers(&[
+            ("User-Agent", "GitHub-Hookshot/c2d5b2d"),
+            ("X-GitHub-Delivery", "72d3162e-cc78-11e3-81ab-4c9367dc0958"),
+            ("X-GitHub-Event", "push"),
+            ("X-GitHub-Hook-ID", "4294967296"),
+            ("X-GitHub-Hook-Installation-Target-ID", "79929171"),
+            ("X-GitHub-Hook-Installation-Target-Type", "integration"),
+            ("X-Hub-Signature", SHA1),
+            ("X-Hub-Signature-256", SHA256),
+        ])
+    }
+
+    fn verify(headers: &HeaderMap, policy: SignaturePolicy) -> Result<()> {
+        validate_headers(headers)?;
+        let payload = Bytes::from_static(PAYLOAD.as_bytes());
+        for signature in signatures_to_verify(headers, policy)? {
+            verify_signature(&payload, &signature, SECRET)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn accepts_recorded_push_delivery() {
+        for policy in [
+            SignaturePolicy::Sha256,
+            SignaturePolicy::Sha256OrSha1,
+            SignaturePolicy::Both,
+        ] {
+            assert!(verify(&push_delivery(), policy).is_ok());
+        }
+    }
+
+    #[test]
+    fn tolerates_missing_optional_headers() {
+        let ping = headers(&[
+            ("X-GitHub-Delivery", "72d3162e-cc78-11e3-81ab-4c9367dc0958"),
+            ("X-GitHub-Event", "ping"),
+            ("X-Hub-Signature-256", SHA256),
+        ]);
+        assert!(verify(&ping, SignaturePolicy::Sha256).is_ok());
+        assert!(verify(&ping, SignaturePolicy::Both).is_err());
+
+        let mut headers = ping.clone();
+        headers.remove("X-GitHub-Delivery");
+        assert!(verify(&headers, SignaturePolicy::Sha256).is_err());
+
+        let mut headers = ping;
+        headers.insert("User-Agent", "curl/8.0".parse().unwrap());
+        assert!(verify(&headers, SignaturePolicy::Sha256).is_err());
+    }
+
+    #[test]
+    fn applies_signature_policy() {
+        let mut sha1_only = push_delivery();
+        sha1_only.remove("X-Hub-Signature-256");
+        assert!(verify(&sha1_only, SignaturePolicy::Sha256).is_err());
+        assert!(verify(&sha1_only, SignaturePolicy::Sha256OrSha1).is_ok());
+        assert!(verify(&sha1_only, SignaturePolicy::Both).is_err());
+
+        let mut tampered = push_delivery();
+        tampered.insert(
+            "X-Hub-Signature",
+            SHA256.replace("sha256", "sha1").parse().unwrap(),
+        );
+        assert!(verify(&tampered, SignaturePolicy::Sha256).is_ok());
+        assert!(verify(&tampered, SignaturePolicy::Both).is_err());
+
+        let mut wrong = push_delivery();
+        wrong.insert(
+            "X-Hub-Signature-256",
+            SHA256.replace("757", "758").parse().unwrap(),
+        );
+        assert!(verify(&wrong, SignaturePolicy::Sha256).is_err());
+    }
+
+    #[test]
+    fn tries_previous_secrets_until_they_expire() {
+        let secret = |name: &str, token: &str, expires_in_hours: i64| CallbackSecret {
+            name: name.to_string(),
+            token: token.to_string(),
+            expires_at: Some(Utc::now() + Duration::hours(expires_in_hours)),
+        };
+        let app_config = AppConfig {
+            callback_token: "new secret".to_string(),
+            previous_callback_tokens: vec![
+                secret("expired", SECRET, -1),
+                secret("2025-rotation", SECRET, 24),
+            ],
+            ..AppConfig::default()
+        };
+        let payload = Bytes::from_static(PAYLOAD.as_bytes());
+        let signatures = signatures_to_verify(&push_delivery(), SignaturePolicy::Both).unwrap();
+        let secrets = app_config.callback_secrets();
+        let (position, matched) = verify_with_secrets(&payload, &signatures, &secrets).unwrap();
+        assert_eq!((position, matched.name.as_str()), (1, "2025-rotation"));
+
+        assert!(verify_with_secrets(&payload, &signatures, &secrets[..1]).is_err());
+    }
+}
@@ -0,0 +1,355 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    app_config::{AppConfig, ConfigSources},
+    audit_log::{read_audit_entries, AuditFilter},
+    replay_delivery,
+    secrets::resolve_secrets,
+    sqlite_storage::SqliteStorage,
+    storage::{create_data_dir, database_path, init_storage, resolve_data_dir, FileStorage},
+    telemetry::init_tracing,
+    version_bump::{bump_version_in_content, find_version, VersionLevel},
+};
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "GitHub app increasing the version of a repository on every push"
+)]
+pub struct Cli {
+    /// Directory holding the configs, tokens, deliveries, audit log and local clones, defaults to
+    /// `$XDG_DATA_HOME/increase_version_app`
+    #[arg(long, env = "DATA_DIR", global = true)]
+    pub data_dir: Option<PathBuf>,
+    #[command(flatten)]
+    pub config: ConfigArgs,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+// Flags overriding the config file and the environment, lists are comma separated
+#[derive(Args, Serialize)]
+pub struct ConfigArgs {
+    /// TOML, YAML or JSON config file, replaces `config/IncreaseAppVersion.json` of the storage
+    #[arg(long = "config", env = "CONFIG_FILE", global = true)]
+    #[serde(skip)]
+    pub config_file: Option<PathBuf>,
+    #[arg(long, global = true)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app_id: Option<u64>,
+    #[arg(long, global = true)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app_name: Option<String>,
+    /// PEM file of the app private key
+    #[arg(long, global = true)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub private_key_file: Option<String>,
+    #[arg(long, global = true)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_to_download: Option<String>,
+    #[arg(long, global = true)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pattern_version_to_search: Option<String>,
+    #[arg(long, global = true, value_delimiter = ',')]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub branch_refs_to_observe: Option<Vec<String>>,
+    #[arg(long, global = true, value_delimiter = ',')]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub whitelist_ips: Option<Vec<String>>,
+    #[arg(long, global = true, value_delimiter = ',')]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deny_ips: Option<Vec<String>>,
+    #[arg(long, global = true)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit_when_sender_is_bot: Option<bool>,
+}
+
+impl ConfigArgs {
+    pub fn sources(&self) -> ConfigSources {
+        let Ok(Value::Object(flags)) = serde_json::to_value(self) else {
+            unreachable!("ConfigArgs serializes to an object");
+        };
+        ConfigSources {
+            file: self.config_file.clone(),
+            flags,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ConfigFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Print the effective config after merging the file, the environment and the flags, with
+    /// the secrets redacted
+    Show {
+        #[arg(long, value_enum, default_value = "toml")]
+        format: ConfigFormat,
+    },
+    /// Run the startup validation and print every problem found
+    Check,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Start the webhook server (default)
+    Serve,
+    /// Increase the version in a local file, the same way the bot does it
+    Bump {
+        #[command(flatten)]
+        target: VersionFileArgs,
+        /// Component to increase, the other ones are kept as they are
+        #[arg(long, value_enum, default_value = "minor")]
+        level: VersionLevel,
+    },
+    /// Print the version found in a local file
+    Show {
+        #[command(flatten)]
+        target: VersionFileArgs,
+    },
+    /// Exit with an error if the bot would not be able to bump the local file
+    Check {
+        #[command(flatten)]
+        target: VersionFileArgs,
+    },
+    /// Run a stored delivery through the webhook pipeline again
+    Replay {
+        /// Value of the `X-GitHub-Delivery` header of the stored delivery
+        delivery_id: String,
+        /// Report the planned bump without committing it
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Print the audit log of the processed deliveries, most recent first
+    Audit {
+        /// Only show the entries of this repository (`owner/name` or local repository name)
+        #[arg(long)]
+        repository: Option<String>,
+        /// Maximum number of entries to print
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+        /// Print the raw JSON entries
+        #[arg(long)]
+        json: bool,
+    },
+    /// Inspect the config of the app
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Copy the config, tokens, deliveries and audit log folders into a SQLite database
+    Import {
+        /// Database to import into, created when missing. Relative to the data directory,
+        /// `app.db` by default
+        #[arg(long, env = "DATABASE_PATH")]
+        database: Option<PathBuf>,
+        /// Directory holding the `config`, `tokens`, `deliveries` and `audit` folders, defaults to
+        /// the data directory
+        #[arg(long)]
+        from: Option<PathBuf>,
+        /// Import even when the database already holds audit entries, they are appended
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Args)]
+pub struct VersionFileArgs {
+    /// File holding the version
+    #[arg(long, default_value = "version.hpp")]
+    pub file: PathBuf,
+    /// Text preceding the version in the file
+    #[arg(long, default_value = "#define VERSION")]
+    pub pattern: String,
+}
+
+fn run_bump(target: &VersionFileArgs, level: VersionLevel) -> Result<()> {
+    let content = fs::read_to_string(&target.file)?;
+    let bump = bump_version_in_content(&content, &target.pattern, level)?;
+    fs::write(&target.file, bump.content)?;
+    println!("{} -> {}", bump.old_version, bump.new_version);
+    Ok(())
+}
+
+fn run_show(target: &VersionFileArgs) -> Result<()> {
+    let content = fs::read_to_string(&target.file)?;
+    let found = find_version(&content, &target.pattern)?;
+    println!("{}", found.version);
+    Ok(())
+}
+
+fn run_check(target: &VersionFileArgs) -> Result<()> {
+    let content = fs::read_to_string(&target.file)?;
+    let bump = bump_version_in_content(&content, &target.pattern, VersionLevel::Minor)?;
+    println!(
+        "OK: {} would be bumped from {} to {}",
+        target.file.display(),
+        bump.old_version,
+        bump.new_version
+    );
+    Ok(())
+}
+
+async fn run_replay(
+    data_dir: Option<PathBuf>,
+    sources: &ConfigSources,
+    delivery_id: &str,
+    dry_run: bool,
+) -> Result<()> {
+    init_tracing()?;
+    init_storage(data_dir)?;
+    let mut app_config = AppConfig::new(sources)?;
+    resolve_secrets(&mut app_config).await?;
+    app_config.dry_run |= dry_run;
+
+    let outcome = replay_delivery(app_config, delivery_id).await?;
+    println!("{}", outcome.describe());
+    Ok(())
+}
+
+fn run_audit(
+    data_dir: Option<PathBuf>,
+    repository: Option<String>,
+    limit: usize,
+    json: bool,
+) -> Result<()> {
+    init_storage(data_dir)?;
+    let filter = AuditFilter {
+        repository,
+        limit: Some(limit),
+    };
+    for entry in read_audit_entries(&filter)? {
+        match json {
+            true => println!("{}", serde_json::to_string(&entry)?),
+            false => println!("{}", entry.describe()),
+        }
+    }
+    Ok(())
+}
+
+fn run_import(
+    data_dir: Option<PathBuf>,
+    database: Option<&Path>,
+    from: Option<PathBuf>,
+    force: bool,
+) -> Result<()> {
+    let data_dir = resolve_data_dir(data_dir);
+    create_data_dir(&data_dir)?;
+    let database = database_path(&data_dir, database);
+    let from = from.unwrap_or(data_dir);
+    let target = SqliteStorage::open(&database)?;
+    let summary = target.import(&FileStorage::new(&from), force)?;
+    println!(
+        "Imported into {}: app config {}, {} repository configs, {} tokens, {} deliveries ({} skipped), {} audit entries",
+        database.display(),
+        match summary.app_config {
+            true => "yes",
+            false => "no",
+        },
+        summary.repository_configs,
+        summary.tokens,
+        summary.deliveries,
+        summary.skipped_deliveries,
+        summary.audit_entries
+    );
+    Ok(())
+}
+
+// TOML has no null, unset optional settings are left out
+fn without_nulls(value: Value) -> Value {
+    match value {
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .filter(|(_, value)| !value.is_null())
+                .map(|(name, value)| (name, without_nulls(value)))
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(without_nulls).collect()),
+        value => value,
+    }
+}
+
+async fn run_config_show(
+    data_dir: Option<PathBuf>,
+    sources: &ConfigSources,
+    format: ConfigFormat,
+) -> Result<()> {
+    init_storage(data_dir)?;
+    let mut app_config = AppConfig::new(sources)?;
+    resolve_secrets(&mut app_config).await?;
+    let config = app_config.redacted();
+    let output = match format {
+        ConfigFormat::Toml => toml::to_string(&without_nulls(config))?,
+        ConfigFormat::Yaml => serde_yaml::to_string(&config)?,
+        ConfigFormat::Json => serde_json::to_string_pretty(&config)?,
+    };
+    println!("{}", output.trim_end());
+    Ok(())
+}
+
+async fn run_config_check(data_dir: Option<PathBuf>, sources: &ConfigSources) -> Result<()> {
+    init_storage(data_dir)?;
+    let mut app_config = AppConfig::new(sources)?;
+    resolve_secrets(&mut app_config).await?;
+    app_config.validate()?;
+    println!("OK: config is valid");
+    Ok(())
+}
+
+// Runs every command besides `serve`, returns the process exit code
+pub async fn run_command(
+    command: Command,
+    data_dir: Option<PathBuf>,
+    sources: ConfigSources,
+) -> i32 {
+    let result = match command {
+        // main starts the server itself and never passes `serve` here
+        Command::Serve => Err(anyhow!(
+            "`serve` starts the server, it is not a one-off command"
+        )),
+        Command::Bump { target, level } => run_bump(&target, level),
+        Command::Show { target } => run_show(&target),
+        Command::Check { target } => run_check(&target),
+        Command::Replay {
+            delivery_id,
+            dry_run,
+        } => run_replay(data_dir, &sources, &delivery_id, dry_run).await,
+        Command::Audit {
+            repository,
+            limit,
+            json,
+        } => run_audit(data_dir, repository, limit, json),
+        Command::Config {
+            command: ConfigCommand::Show { format },
+        } => run_config_show(data_dir, &sources, format).await,
+        Command::Config {
+            command: ConfigCommand::Check,
+        } => run_config_check(data_dir, &sources).await,
+        Command::Import {
+            database,
+            from,
+            force,
+        } => run_import(data_dir, database.as_deref(), from, force),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("Error: {err}");
+            1
+        }
+    }
+}
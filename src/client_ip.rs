@@ -0,0 +1,178 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{bail, ensure, Result};
+use axum::http::HeaderMap;
+use tokio::{io::AsyncReadExt, net::TcpStream};
+
+use crate::app_config::SecurityConfig;
+
+static PROXY_V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+static PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// `for=` values may be quoted and carry a port, `"[2001:db8::1]:4711"` or `192.0.2.60:80`
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim().trim_matches('"');
+    if let Ok(ip) = hop.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Some(rest) = hop.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    hop.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+}
+
+// Hops in the order they were added, the closest proxy last
+fn forwarded_hops(headers: &HeaderMap) -> Vec<String> {
+    let forwarded: Vec<&str> = headers
+        .get_all("forwarded")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded
+            .iter()
+            .flat_map(|value| value.split(','))
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                    .map(|(_, value)| value.to_string())
+                    .unwrap_or_default()
+            })
+            .collect();
+    }
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::to_string)
+        .collect()
+}
+
+// Walks the forwarded chain from the closest hop and stops at the first address that is not a
+// trusted proxy, headers sent by anyone else are ignored
+pub fn resolve_client_ip(peer: IpAddr, headers: &HeaderMap, trusted: &SecurityConfig) -> IpAddr {
+    let mut client = peer.to_canonical();
+    if !trusted.contains(client) {
+        return client;
+    }
+    for hop in forwarded_hops(headers).iter().rev() {
+        let Some(ip) = parse_hop(hop) else {
+            break;
+        };
+        client = ip.to_canonical();
+        if !trusted.contains(client) {
+            break;
+        }
+    }
+    client
+}
+
+// Returns the length of the address block, see https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+fn parse_proxy_v2_header(header: &[u8; 16]) -> Result<usize> {
+    ensure!(
+        header[..12] == PROXY_V2_SIGNATURE,
+        "missing PROXY protocol v2 signature"
+    );
+    ensure!(header[12] >> 4 == 2, "unsupported PROXY protocol version");
+    Ok(u16::from_be_bytes([header[14], header[15]]) as usize)
+}
+
+// `None` for `LOCAL` connections (health checks of the proxy itself) and unknown families
+fn parse_proxy_v2_addresses(command: u8, family: u8, block: &[u8]) -> Result<Option<SocketAddr>> {
+    match command & 0x0F {
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => bail!("unsupported PROXY protocol command"),
+    }
+    match family >> 4 {
+        0x1 => {
+            ensure!(block.len() >= 12, "truncated PROXY protocol IPv4 addresses");
+            let ip = Ipv4Addr::new(block[0], block[1], block[2], block[3]);
+            let port = u16::from_be_bytes([block[8], block[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        0x2 => {
+            ensure!(block.len() >= 36, "truncated PROXY protocol IPv6 addresses");
+            let octets: [u8; 16] = block[..16].try_into()?;
+            let port = u16::from_be_bytes([block[32], block[33]]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                port,
+            )))
+        }
+        _ => Ok(None),
+    }
+}
+
+// Consumes the PROXY protocol v2 header sent by the load balancer at the start of the connection
+pub async fn read_proxy_header(stream: &mut TcpStream) -> Result<Option<SocketAddr>> {
+    tokio::time::timeout(PROXY_HEADER_TIMEOUT, async {
+        let mut header = [0u8; 16];
+        stream.read_exact(&mut header).await?;
+        let mut block = vec![0u8; parse_proxy_v2_header(&header)?];
+        stream.read_exact(&mut block).await?;
+        parse_proxy_v2_addresses(header[12], header[13], &block)
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trusted() -> SecurityConfig {
+        SecurityConfig::from_entries(&["10.0.0.0/8".to_string()]).unwrap()
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "140.82.115.1".parse().unwrap());
+        let client = resolve_client_ip("203.0.113.9".parse().unwrap(), &headers, &trusted());
+        assert_eq!(client, "203.0.113.9".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn skips_trusted_hops_of_the_chain() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            "1.2.3.4, 140.82.115.1, 10.0.0.7".parse().unwrap(),
+        );
+        let client = resolve_client_ip("10.0.0.2".parse().unwrap(), &headers, &trusted());
+        assert_eq!(client, "140.82.115.1".parse::<IpAddr>().unwrap());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "forwarded",
+            "for=1.2.3.4, for=\"[2001:db8::1]:4711\";proto=https"
+                .parse()
+                .unwrap(),
+        );
+        let client = resolve_client_ip("10.0.0.2".parse().unwrap(), &headers, &trusted());
+        assert_eq!(client, "2001:db8::1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn parses_proxy_v2_header() {
+        let mut data = PROXY_V2_SIGNATURE.to_vec();
+        data.extend([0x21, 0x11, 0, 12]);
+        let header: [u8; 16] = data.try_into().unwrap();
+        assert_eq!(parse_proxy_v2_header(&header).unwrap(), 12);
+
+        let block = [140, 82, 115, 1, 10, 0, 0, 2, 0x1F, 0x90, 0x0B, 0xB8];
+        let source = parse_proxy_v2_addresses(0x21, 0x11, &block).unwrap();
+        assert_eq!(source, Some("140.82.115.1:8080".parse().unwrap()));
+        assert_eq!(parse_proxy_v2_addresses(0x20, 0x11, &block).unwrap(), None);
+
+        let mut header = header;
+        header[0] = b'P';
+        assert!(parse_proxy_v2_header(&header).is_err());
+    }
+}
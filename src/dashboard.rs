@@ -0,0 +1,244 @@
+use std::{collections::HashMap, fmt::Write};
+
+use axum::{
+    extract::{Query, State},
+    response::Html,
+};
+
+use crate::{
+    admin::LimitQuery,
+    app_config::{list_installation_ids, AppConfig, RepositoryConfig},
+    audit_log::{read_audit_entries, AuditEntry, AuditFilter},
+};
+
+// Only the most recent audit entries are shown, the log itself is unbounded
+static AUDIT_ENTRIES_READ: usize = 1000;
+
+static STYLE: &str =
+    "body{font-family:sans-serif;margin:2em}table{border-collapse:collapse;margin-bottom:2em}\
+td,th{border:1px solid #ccc;padding:4px 8px;text-align:left}.failed{color:#b00}";
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn commit_link(entry: &AuditEntry) -> String {
+    let Some(sha) = &entry.commit_sha else {
+        return "-".to_string();
+    };
+    let short_sha = escape(&sha[..sha.len().min(7)]);
+    // Local repositories have no `owner/name` and nowhere to link to
+    match entry.repository.contains('/') {
+        true => format!(
+            "<a href=\"https://github.com/{}/commit/{}\">{short_sha}</a>",
+            escape(&entry.repository),
+            escape(sha)
+        ),
+        false => short_sha,
+    }
+}
+
+fn version_change(entry: &AuditEntry) -> String {
+    match (&entry.old_version, &entry.new_version) {
+        (Some(old), Some(new)) => escape(&format!("{old} -> {new}")),
+        _ => "-".to_string(),
+    }
+}
+
+fn installations_section(html: &mut String, app_config: &AppConfig) {
+    html.push_str("<h2>Installations</h2><table><tr><th>Installation</th><th>File</th><th>Pattern</th><th>Refs</th><th>Bot commits</th><th>Dry run</th></tr>");
+    for installation_id in list_installation_ids().unwrap_or_default() {
+        let Ok(config) = RepositoryConfig::read(installation_id) else {
+            continue;
+        };
+        let _ = write!(
+            html,
+            "<tr><td>{installation_id}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape(&config.file_to_donwload),
+            escape(&config.pattern_version_to_search),
+            escape(&config.branch_refs_to_observe.join(", ")),
+            config.commit_when_sender_is_bot,
+            config.dry_run || app_config.dry_run
+        );
+    }
+    html.push_str("</table>");
+
+    if app_config.local_repositories.is_empty() {
+        return;
+    }
+    html.push_str("<h2>Local repositories</h2><table><tr><th>Name</th><th>Remote</th><th>File</th><th>Pattern</th><th>Refs</th></tr>");
+    for repo in &app_config.local_repositories {
+        let _ = write!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape(&repo.name),
+            escape(&repo.remote_url),
+            escape(&repo.file_to_download),
+            escape(&repo.pattern_version_to_search),
+            escape(&repo.branch_refs_to_observe.join(", "))
+        );
+    }
+    html.push_str("</table>");
+}
+
+fn repositories_section(html: &mut String, entries: &[AuditEntry]) {
+    html.push_str("<h2>Repositories</h2><table><tr><th>Repository</th><th>Current version</th><th>Last outcome</th><th>Last delivery</th></tr>");
+    // Entries are most recent first: the first one of a repository is its last delivery and the
+    // first non dry run bump its current version
+    let mut order: Vec<&str> = Vec::new();
+    let mut repositories: HashMap<&str, (&AuditEntry, Option<&str>)> = HashMap::new();
+    for entry in entries {
+        let (_, current_version) = repositories
+            .entry(entry.repository.as_str())
+            .or_insert_with(|| {
+                order.push(&entry.repository);
+                (entry, None)
+            });
+        if current_version.is_none() && !entry.dry_run {
+            *current_version = entry.new_version.as_deref();
+        }
+    }
+    for repository in order {
+        let (last, current_version) = repositories[repository];
+        let _ = write!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape(repository),
+            escape(current_version.unwrap_or("-")),
+            escape(&last.outcome),
+            escape(&last.timestamp)
+        );
+    }
+    html.push_str("</table>");
+}
+
+fn bumps_section(html: &mut String, entries: &[AuditEntry], limit: usize) {
+    let _ = write!(html, "<h2>Last {limit} bumps</h2>");
+    html.push_str("<table><tr><th>Time</th><th>Repository</th><th>Ref</th><th>Version</th><th>Pusher</th><th>Commit</th></tr>");
+    for entry in entries
+        .iter()
+        .filter(|entry| entry.new_version.is_some())
+        .take(limit)
+    {
+        let _ = write!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}{}</td><td>{}</td><td>{}</td></tr>",
+            escape(&entry.timestamp),
+            escape(&entry.repository),
+            escape(&entry.ref_),
+            version_change(entry),
+            if entry.dry_run { " (dry run)" } else { "" },
+            escape(entry.pusher.as_deref().unwrap_or("-")),
+            commit_link(entry)
+        );
+    }
+    html.push_str("</table>");
+}
+
+fn failures_section(html: &mut String, entries: &[AuditEntry], limit: usize) {
+    html.push_str("<h2>Failed deliveries</h2><table><tr><th>Time</th><th>Repository</th><th>Delivery</th><th>Error</th><th></th></tr>");
+    for entry in entries
+        .iter()
+        .filter(|entry| entry.error.is_some())
+        .take(limit)
+    {
+        let replay_button = match &entry.delivery_id {
+            Some(delivery_id) => format!(
+                "<form method=\"post\" action=\"/admin/deliveries/{}/replay\"><button>Replay</button></form>",
+                escape(delivery_id)
+            ),
+            None => String::new(),
+        };
+        let _ = write!(
+            html,
+            "<tr class=\"failed\"><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{replay_button}</td></tr>",
+            escape(&entry.timestamp),
+            escape(&entry.repository),
+            escape(entry.delivery_id.as_deref().unwrap_or("-")),
+            escape(entry.error.as_deref().unwrap_or_default())
+        );
+    }
+    html.push_str("</table>");
+}
+
+pub async fn dashboard(
+    State(app_config): State<AppConfig>,
+    Query(query): Query<LimitQuery>,
+) -> Html<String> {
+    let limit = query.limit.unwrap_or(20);
+    let filter = AuditFilter {
+        limit: Some(AUDIT_ENTRIES_READ),
+        ..AuditFilter::default()
+    };
+    let entries = read_audit_entries(&filter).unwrap_or_default();
+
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{}</title><style>{STYLE}</style></head><body><h1>{}</h1>",
+        escape(&app_config.app_name),
+        escape(&app_config.app_name)
+    );
+    installations_section(&mut html, &app_config);
+    repositories_section(&mut html, &entries);
+    bumps_section(&mut html, &entries, limit);
+    failures_section(&mut html, &entries, limit);
+    html.push_str("</body></html>");
+    Html(html)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn entry(
+        repository: &str,
+        outcome: &str,
+        new_version: Option<&str>,
+        dry_run: bool,
+    ) -> AuditEntry {
+        serde_json::from_value(json!({
+            "timestamp": format!("{repository}-{outcome}"),
+            "delivery_id": null,
+            "repository": repository,
+            "ref": "refs/heads/main",
+            "pusher": null,
+            "commits": [],
+            "old_version": null,
+            "new_version": new_version,
+            "commit_sha": null,
+            "dry_run": dry_run,
+            "replay": false,
+            "outcome": outcome,
+            "error": null
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn lists_each_repository_once_with_its_current_version() {
+        // Most recent first
+        let entries = vec![
+            entry("octo/app", "dry_run", Some("1.3.0"), true),
+            entry("octo/lib", "failed", None, false),
+            entry("octo/app", "bumped", Some("1.2.0"), false),
+            entry("octo/app", "bumped", Some("1.1.0"), false),
+        ];
+        let mut html = String::new();
+        repositories_section(&mut html, &entries);
+        assert_eq!(html.matches("<td>octo/app</td>").count(), 1);
+        assert!(html.contains(
+            "<tr><td>octo/app</td><td>1.2.0</td><td>dry_run</td><td>octo/app-dry_run</td></tr>"
+        ));
+        assert!(html.contains(
+            "<tr><td>octo/lib</td><td>-</td><td>failed</td><td>octo/lib-failed</td></tr>"
+        ));
+        assert!(html.find("octo/app").unwrap() < html.find("octo/lib").unwrap());
+    }
+}
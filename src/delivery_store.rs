@@ -0,0 +1,236 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use anyhow::{bail, ensure, Result};
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, HeaderName, HeaderValue},
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::{
+    app_errors::AppErrors,
+    storage::{storage, Storage},
+};
+
+static DELIVERY_ID_HEADER: &str = "X-GitHub-Delivery";
+static PRUNE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+// Raw webhook, kept so it can be replayed through the same pipeline later
+#[derive(Serialize, Deserialize)]
+pub struct StoredDelivery {
+    pub id: String,
+    pub received_at: String,
+    pub headers: BTreeMap<String, String>,
+    pub payload: String,
+}
+
+impl StoredDelivery {
+    pub fn header_map(&self) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+        Ok(headers)
+    }
+
+    pub fn payload_bytes(&self) -> Bytes {
+        Bytes::from(self.payload.clone())
+    }
+}
+
+fn is_valid_delivery_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+pub fn delivery_id(headers: &HeaderMap) -> Option<String> {
+    let id = headers.get(DELIVERY_ID_HEADER)?.to_str().ok()?;
+    is_valid_delivery_id(id).then(|| id.to_string())
+}
+
+pub fn save_delivery(headers: &HeaderMap, payload: &Bytes) -> Result<()> {
+    store_delivery(storage(), headers, payload)
+}
+
+fn store_delivery(storage: &dyn Storage, headers: &HeaderMap, payload: &Bytes) -> Result<()> {
+    let Some(id) = delivery_id(headers) else {
+        bail!(AppErrors::HeaderParsingError(DELIVERY_ID_HEADER));
+    };
+
+    let headers = headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    let delivery = StoredDelivery {
+        id,
+        received_at: Utc::now().to_rfc3339(),
+        headers,
+        payload: String::from_utf8(payload.to_vec())?,
+    };
+
+    let data = serde_json::to_string(&delivery)?;
+    storage.write_delivery(&delivery.id, &data)
+}
+
+pub fn read_delivery(id: &str) -> Result<StoredDelivery> {
+    load_delivery(storage(), id)
+}
+
+fn load_delivery(storage: &dyn Storage, id: &str) -> Result<StoredDelivery> {
+    ensure!(
+        is_valid_delivery_id(id),
+        AppErrors::DeliveryNotFound(id.to_string())
+    );
+    let Ok(Some(data)) = storage.read_delivery(id) else {
+        bail!(AppErrors::DeliveryNotFound(id.to_string()));
+    };
+    let delivery = serde_json::from_str::<StoredDelivery>(data.as_str())?;
+    Ok(delivery)
+}
+
+#[derive(Serialize)]
+pub struct DeliverySummary {
+    pub id: String,
+    pub received_at: String,
+    pub event: Option<String>,
+}
+
+// Stored deliveries, most recent first
+pub fn list_deliveries(limit: usize) -> Result<Vec<DeliverySummary>> {
+    let mut deliveries: Vec<DeliverySummary> = Vec::new();
+    for data in storage().list_deliveries()? {
+        let Ok(delivery) = serde_json::from_str::<StoredDelivery>(data.as_str()) else {
+            continue;
+        };
+        let event = delivery
+            .headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("X-GitHub-Event"))
+            .map(|(_, value)| value.clone());
+        deliveries.push(DeliverySummary {
+            id: delivery.id,
+            received_at: delivery.received_at,
+            event,
+        });
+    }
+    deliveries.sort_by(|a, b| b.received_at.cmp(&a.received_at));
+    deliveries.truncate(limit);
+    Ok(deliveries)
+}
+
+// Removes the deliveries older than `retention_days`
+pub fn prune_deliveries(retention_days: u32) -> Result<()> {
+    let Some(retention) = TimeDelta::try_days(retention_days.into()) else {
+        bail!(AppErrors::InvalidEvironmentVariable(
+            "delivery_retention_days".to_string(),
+            "invalid value"
+        ));
+    };
+    remove_deliveries_before(storage(), Utc::now() - retention)
+}
+
+fn remove_deliveries_before(storage: &dyn Storage, oldest_allowed: DateTime<Utc>) -> Result<()> {
+    for data in storage.list_deliveries()? {
+        let Ok(delivery) = serde_json::from_str::<StoredDelivery>(data.as_str()) else {
+            warn!("Failed to parse a stored delivery");
+            continue;
+        };
+        let Ok(received_at) = DateTime::parse_from_rfc3339(&delivery.received_at) else {
+            continue;
+        };
+        if received_at < oldest_allowed {
+            info!("Removing expired delivery {}", delivery.id);
+            storage.delete_delivery(&delivery.id)?;
+        }
+    }
+    Ok(())
+}
+
+// Prunes at startup and then every hour, off the async workers since it reads every delivery
+pub fn spawn_delivery_pruner(retention_days: u32) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_PERIOD);
+        loop {
+            interval.tick().await;
+            match tokio::task::spawn_blocking(move || prune_deliveries(retention_days)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => error!("Failed to prune stored deliveries: {err}"),
+                Err(err) => error!("Delivery pruning panicked: {err}"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::storage::FileStorage;
+
+    fn file_storage(dir: &TempDir) -> FileStorage {
+        let storage = FileStorage::new(dir.path());
+        storage.prepare().unwrap();
+        storage
+    }
+
+    fn delivery_headers(id: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(DELIVERY_ID_HEADER, HeaderValue::from_str(id).unwrap());
+        headers.insert("X-GitHub-Event", HeaderValue::from_static("push"));
+        headers
+    }
+
+    #[test]
+    fn saves_and_reads_a_delivery() {
+        let dir = TempDir::new().unwrap();
+        let storage = file_storage(&dir);
+        let payload = Bytes::from_static(b"{\"ref\":\"refs/heads/main\"}");
+        store_delivery(&storage, &delivery_headers("72d3162e-cc78"), &payload).unwrap();
+
+        let delivery = load_delivery(&storage, "72d3162e-cc78").unwrap();
+        assert_eq!(delivery.payload_bytes(), payload);
+        assert_eq!(delivery.header_map().unwrap()["x-github-event"], "push");
+    }
+
+    #[test]
+    fn rejects_invalid_delivery_ids() {
+        let dir = TempDir::new().unwrap();
+        let storage = file_storage(&dir);
+        let payload = Bytes::from_static(b"{}");
+        assert!(store_delivery(&storage, &delivery_headers("../config"), &payload).is_err());
+        assert!(store_delivery(&storage, &HeaderMap::new(), &payload).is_err());
+
+        let Err(err) = load_delivery(&storage, "../config/IncreaseAppVersion") else {
+            panic!("expected an error");
+        };
+        assert!(matches!(
+            err.downcast_ref::<AppErrors>(),
+            Some(AppErrors::DeliveryNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn removes_deliveries_past_the_retention() {
+        let dir = TempDir::new().unwrap();
+        let storage = file_storage(&dir);
+        for (id, age_days) in [("old", 20), ("recent", 2)] {
+            let delivery = StoredDelivery {
+                id: id.to_string(),
+                received_at: (Utc::now() - TimeDelta::days(age_days)).to_rfc3339(),
+                headers: BTreeMap::new(),
+                payload: "{}".to_string(),
+            };
+            let data = serde_json::to_string(&delivery).unwrap();
+            storage.write_delivery(id, &data).unwrap();
+        }
+
+        remove_deliveries_before(&storage, Utc::now() - TimeDelta::days(14)).unwrap();
+        assert!(load_delivery(&storage, "old").is_err());
+        assert!(load_delivery(&storage, "recent").is_ok());
+    }
+}
@@ -0,0 +1,144 @@
+use axum::{extract::State, http::StatusCode, Extension, Json};
+use jsonwebtoken::EncodingKey;
+use serde::Serialize;
+
+use crate::{
+    app_config::{AppConfig, SecurityConfig},
+    hook_subnets::SharedSecurityConfig,
+    storage::{storage, Storage},
+};
+
+#[derive(Serialize)]
+pub struct ReadinessChecks {
+    pub config_loaded: bool,
+    pub private_key_valid: bool,
+    pub hook_subnets_loaded: bool,
+    pub storage_writable: bool,
+}
+
+#[derive(Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: ReadinessChecks,
+}
+
+#[derive(Serialize)]
+pub struct BuildInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub git_commit: Option<&'static str>,
+    pub profile: &'static str,
+}
+
+pub async fn healthz() -> &'static str {
+    "OK"
+}
+
+pub async fn readyz(
+    State(app_config): State<AppConfig>,
+    Extension(security): Extension<SharedSecurityConfig>,
+) -> (StatusCode, Json<Readiness>) {
+    let (status, readiness) = readiness(&app_config, &security.load(), storage());
+    (status, Json(readiness))
+}
+
+fn readiness(
+    app_config: &AppConfig,
+    security: &SecurityConfig,
+    storage: &dyn Storage,
+) -> (StatusCode, Readiness) {
+    let checks = ReadinessChecks {
+        config_loaded: !app_config.callback_token.is_empty() && app_config.app_id != 0,
+        private_key_valid: EncodingKey::from_rsa_pem(app_config.private_signature.as_bytes())
+            .is_ok(),
+        hook_subnets_loaded: !security.subnets.is_empty(),
+        storage_writable: storage.is_writable(),
+    };
+    let ready = checks.config_loaded
+        && checks.private_key_valid
+        && checks.hook_subnets_loaded
+        && checks.storage_writable;
+
+    let status = match ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Readiness { ready, checks })
+}
+
+pub async fn version() -> Json<BuildInfo> {
+    Json(BuildInfo {
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        git_commit: option_env!("GIT_COMMIT"),
+        profile: match cfg!(debug_assertions) {
+            true => "debug",
+            false => "release",
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::storage::FileStorage;
+
+    fn ready_config() -> AppConfig {
+        AppConfig {
+            app_id: 42,
+            callback_token: "secret".to_string(),
+            private_signature: include_str!("../tests/fixtures/test_private_key.pem").to_string(),
+            ..AppConfig::default()
+        }
+    }
+
+    fn hook_subnets(subnets: &[&str]) -> SecurityConfig {
+        SecurityConfig {
+            subnets: subnets
+                .iter()
+                .map(|subnet| subnet.parse().unwrap())
+                .collect(),
+        }
+    }
+
+    fn check(app_config: &AppConfig, security: &SecurityConfig) -> (StatusCode, Value) {
+        let dir = TempDir::new().unwrap();
+        let storage = FileStorage::new(dir.path());
+        storage.prepare().unwrap();
+        let (status, readiness) = readiness(app_config, security, &storage);
+        (status, serde_json::to_value(readiness).unwrap())
+    }
+
+    #[test]
+    fn ready_when_every_check_passes() {
+        let (status, body) = check(&ready_config(), &hook_subnets(&["192.30.252.0/22"]));
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["ready"], json!(true));
+    }
+
+    #[test]
+    fn names_the_failing_check() {
+        let mut app_config = ready_config();
+        app_config.private_signature = "not a key".to_string();
+        let (status, body) = check(&app_config, &hook_subnets(&["192.30.252.0/22"]));
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            body["checks"],
+            json!({
+                "config_loaded": true,
+                "private_key_valid": false,
+                "hook_subnets_loaded": true,
+                "storage_writable": true
+            })
+        );
+
+        let (status, body) = check(&ready_config(), &hook_subnets(&[]));
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["ready"], json!(false));
+        assert_eq!(body["checks"]["hook_subnets_loaded"], json!(false));
+        assert_eq!(body["checks"]["private_key_valid"], json!(true));
+    }
+}
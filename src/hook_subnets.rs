@@ -0,0 +1,187 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use arc_swap::ArcSwap;
+use ipnet::IpNet;
+use tracing::{error, info, warn};
+
+use crate::{
+    app_apis::get_github_environment_details,
+    app_config::SecurityConfig,
+    storage::{storage, Storage},
+};
+
+pub type SharedSecurityConfig = Arc<ArcSwap<SecurityConfig>>;
+
+static HOOK_SUBNETS_CACHE_KEY: &str = "github_meta_hooks";
+// First retry delay while no subnets are known, doubled on every failure up to the refresh period
+static INITIAL_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+fn save_cached_subnets(storage: &dyn Storage, security: &SecurityConfig) -> Result<()> {
+    let subnets: Vec<String> = security.subnets.iter().map(IpNet::to_string).collect();
+    let data = serde_json::to_string(&subnets)?;
+    storage.write_cache_entry(HOOK_SUBNETS_CACHE_KEY, &data)
+}
+
+fn read_cached_subnets(storage: &dyn Storage) -> Result<SecurityConfig> {
+    let data = storage
+        .read_cache_entry(HOOK_SUBNETS_CACHE_KEY)?
+        .unwrap_or_default();
+    let subnets = serde_json::from_str::<Vec<String>>(data.as_str())?
+        .iter()
+        .filter_map(|subnet| subnet.parse().ok())
+        .collect();
+    Ok(SecurityConfig { subnets })
+}
+
+// Fetches the GitHub hook subnets, falling back on the last good list when `/meta` is unreachable
+pub async fn load_hook_subnets() -> SecurityConfig {
+    match get_github_environment_details().await {
+        Ok(security) => {
+            if let Err(err) = save_cached_subnets(storage(), &security) {
+                warn!("Failed to cache hook subnets: {err}");
+            }
+            security
+        }
+        Err(err) => {
+            error!("failed to obtain security settings: {err}");
+            cached_subnets(storage())
+        }
+    }
+}
+
+fn cached_subnets(storage: &dyn Storage) -> SecurityConfig {
+    match read_cached_subnets(storage) {
+        Ok(security) => {
+            warn!(
+                "Using {} cached hook subnets until /meta is reachable",
+                security.subnets.len()
+            );
+            security
+        }
+        Err(_) => {
+            error!(
+                "No cached hook subnets, GitHub deliveries are rejected until /meta is reachable"
+            );
+            SecurityConfig {
+                subnets: Vec::new(),
+            }
+        }
+    }
+}
+
+// Returns the added and the removed subnets
+fn log_changes(current: &SecurityConfig, fetched: &SecurityConfig) -> (Vec<IpNet>, Vec<IpNet>) {
+    let added: Vec<IpNet> = fetched
+        .subnets
+        .iter()
+        .filter(|subnet| !current.subnets.contains(subnet))
+        .copied()
+        .collect();
+    let removed: Vec<IpNet> = current
+        .subnets
+        .iter()
+        .filter(|subnet| !fetched.subnets.contains(subnet))
+        .copied()
+        .collect();
+    for subnet in &added {
+        info!("GitHub hook subnet added: {subnet}");
+    }
+    for subnet in &removed {
+        info!("GitHub hook subnet removed: {subnet}");
+    }
+    (added, removed)
+}
+
+async fn refresh_hook_subnets(shared: &SharedSecurityConfig) -> bool {
+    let fetched = match get_github_environment_details().await {
+        Ok(fetched) => fetched,
+        Err(err) => {
+            warn!("Failed to refresh hook subnets, keeping the current ones: {err}");
+            return false;
+        }
+    };
+
+    log_changes(&shared.load(), &fetched);
+    if let Err(err) = save_cached_subnets(storage(), &fetched) {
+        warn!("Failed to cache hook subnets: {err}");
+    }
+    shared.store(Arc::new(fetched));
+    true
+}
+
+// Without any subnet every GitHub delivery is rejected, so retry quickly until a first success
+fn next_refresh_delay(has_subnets: bool, failures: u32, period: Duration) -> Duration {
+    match has_subnets {
+        true => period,
+        false => INITIAL_RETRY_DELAY
+            .saturating_mul(2u32.saturating_pow(failures))
+            .min(period),
+    }
+}
+
+pub fn spawn_hook_subnets_refresher(shared: SharedSecurityConfig, refresh_minutes: u64) {
+    let period = Duration::from_secs(refresh_minutes.max(1) * 60);
+    tokio::spawn(async move {
+        let mut failures = 0;
+        loop {
+            let has_subnets = !shared.load().subnets.is_empty();
+            tokio::time::sleep(next_refresh_delay(has_subnets, failures, period)).await;
+            match refresh_hook_subnets(&shared).await {
+                true => failures = 0,
+                false => failures += 1,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::storage::FileStorage;
+
+    fn security(subnets: &[&str]) -> SecurityConfig {
+        SecurityConfig {
+            subnets: subnets
+                .iter()
+                .map(|subnet| subnet.parse().unwrap())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn logs_added_and_removed_subnets() {
+        let current = security(&["192.30.252.0/22", "185.199.108.0/22"]);
+        let fetched = security(&["192.30.252.0/22", "140.82.112.0/20"]);
+        let (added, removed) = log_changes(&current, &fetched);
+        assert_eq!(added, security(&["140.82.112.0/20"]).subnets);
+        assert_eq!(removed, security(&["185.199.108.0/22"]).subnets);
+        assert_eq!(log_changes(&fetched, &fetched), (Vec::new(), Vec::new()));
+    }
+
+    #[test]
+    fn falls_back_on_the_cached_subnets() {
+        let dir = TempDir::new().unwrap();
+        let storage = FileStorage::new(dir.path());
+        storage.prepare().unwrap();
+        assert!(cached_subnets(&storage).subnets.is_empty());
+
+        let fetched = security(&["192.30.252.0/22", "2a0a:a440::/29"]);
+        save_cached_subnets(&storage, &fetched).unwrap();
+        assert_eq!(cached_subnets(&storage).subnets, fetched.subnets);
+    }
+
+    #[test]
+    fn retries_with_backoff_until_subnets_are_known() {
+        let period = Duration::from_secs(60);
+        assert_eq!(next_refresh_delay(false, 0, period), Duration::from_secs(5));
+        assert_eq!(
+            next_refresh_delay(false, 2, period),
+            Duration::from_secs(20)
+        );
+        assert_eq!(next_refresh_delay(false, 40, period), period);
+        assert_eq!(next_refresh_delay(true, 0, period), period);
+    }
+}
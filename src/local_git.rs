@@ -0,0 +1,296 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, ensure, Result};
+use tokio::{process::Command, sync::Mutex};
+use tracing::{info, instrument};
+
+use crate::{
+    app_config::LocalRepositoryConfig,
+    app_errors::AppErrors,
+    storage::data_dir,
+    version_bump::{commit_message, increase_version_in_content, unified_diff},
+    worker::{BumpReport, DeliveryOutcome},
+};
+
+static LOCAL_REPOS_PATH: &str = "repositories";
+
+// Serializes the work done on the local clones, two deliveries must not share a working tree
+static LOCAL_GIT_LOCK: Mutex<()> = Mutex::const_new(());
+
+pub fn create_local_repos_folder() -> Result<()> {
+    let path = get_local_repos_path();
+    if !path.exists() {
+        fs::create_dir(path)?;
+    }
+    Ok(())
+}
+
+pub fn get_local_repos_path() -> PathBuf {
+    data_dir().join(LOCAL_REPOS_PATH)
+}
+
+// Git runs `GIT_SSH_COMMAND` through the shell, the key path is single quoted so spaces or `;` in it
+// stay part of the path
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+async fn run_git(
+    work_dir: &Path,
+    repo_config: &LocalRepositoryConfig,
+    args: &[&str],
+) -> Result<String> {
+    let mut command = Command::new("git");
+    command.arg("-C").arg(work_dir).args(args);
+    if let Some(ssh_key_path) = &repo_config.ssh_key_path {
+        command.env(
+            "GIT_SSH_COMMAND",
+            format!("ssh -i {} -o IdentitiesOnly=yes", shell_quote(ssh_key_path)),
+        );
+    }
+
+    let output = command.output().await?;
+    if !output.status.success() {
+        let err = format!(
+            "git {} exited with {}: {}",
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        bail!(AppErrors::GitCommandFailed(err));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+// Clones or fetches `repo_config` under `work_root`, increases the version on `ref_` and pushes it back
+#[instrument(skip_all, fields(repository = %repo_config.name))]
+pub async fn bump_local_repository(
+    app_name: &str,
+    repo_config: &LocalRepositoryConfig,
+    ref_: &str,
+    work_root: &Path,
+    dry_run: bool,
+) -> Result<DeliveryOutcome> {
+    ensure!(
+        !repo_config.name.is_empty()
+            && !repo_config.name.contains(['/', '\\'])
+            && repo_config.name != ".."
+            && repo_config.name != ".",
+        AppErrors::UnknownLocalRepository(repo_config.name.clone())
+    );
+    let Some(branch) = ref_.strip_prefix("refs/heads/") else {
+        bail!(AppErrors::GitCommandFailed(format!(
+            "Only branch refs are supported, got `{ref_}`"
+        )));
+    };
+
+    let dry_run = dry_run || repo_config.dry_run;
+
+    let _guard = LOCAL_GIT_LOCK.lock().await;
+    let repo_dir = work_root.join(&repo_config.name);
+    if !repo_dir.exists() {
+        info!("Cloning local repository {}", repo_config.name);
+        run_git(
+            work_root,
+            repo_config,
+            &["clone", &repo_config.remote_url, &repo_config.name],
+        )
+        .await?;
+    } else {
+        run_git(&repo_dir, repo_config, &["fetch", "origin"]).await?;
+    }
+
+    let remote_branch = format!("origin/{branch}");
+    run_git(
+        &repo_dir,
+        repo_config,
+        &["checkout", "-f", "-B", branch, &remote_branch],
+    )
+    .await?;
+
+    let last_author = run_git(&repo_dir, repo_config, &["log", "-1", "--format=%an"]).await?;
+    if last_author.trim() == app_name {
+        info!("The last commit was made by this bot, will ignore that one!");
+        return Ok(DeliveryOutcome::BotSkip);
+    }
+
+    let file_path = repo_dir.join(&repo_config.file_to_download);
+    let content = fs::read_to_string(&file_path)?;
+    let bump = increase_version_in_content(&content, &repo_config.pattern_version_to_search)?;
+    let mut report = BumpReport {
+        commit_message: commit_message(&bump.new_version),
+        old_version: bump.old_version,
+        new_version: bump.new_version,
+        diff: unified_diff(&repo_config.file_to_download, &content, &bump.content),
+        dry_run,
+        commit_sha: None,
+    };
+    if dry_run {
+        info!(
+            "Dry run, would commit \"{}\" with:\n{}",
+            report.commit_message, report.diff
+        );
+        return Ok(DeliveryOutcome::Bumped(report));
+    }
+    fs::write(&file_path, bump.content)?;
+
+    let user_name = format!("user.name={app_name}");
+    let user_email = format!("user.email={app_name}@localhost");
+    run_git(
+        &repo_dir,
+        repo_config,
+        &[
+            "-c",
+            &user_name,
+            "-c",
+            &user_email,
+            "commit",
+            "-a",
+            "-m",
+            &report.commit_message,
+        ],
+    )
+    .await?;
+
+    let push_ref = format!("HEAD:{ref_}");
+    run_git(&repo_dir, repo_config, &["push", "origin", &push_ref]).await?;
+    info!(
+        "Pushed version {} -> {} to {} {}",
+        report.old_version, report.new_version, repo_config.name, ref_
+    );
+
+    let commit_sha = run_git(&repo_dir, repo_config, &["rev-parse", "HEAD"]).await?;
+    report.commit_sha = Some(commit_sha.trim().to_string());
+    Ok(DeliveryOutcome::Bumped(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command as StdCommand;
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = StdCommand::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args([
+                "-c",
+                "user.name=Developer",
+                "-c",
+                "user.email=dev@localhost",
+            ])
+            .args(args)
+            .output()
+            .expect("git is available");
+        assert!(output.status.success(), "git {args:?} failed");
+        String::from_utf8_lossy(&output.stdout).to_string()
+    }
+
+    // Bare `remote.git` holding a `version.hpp` at 1.4.2 on `main`, with a work root and a config for it
+    fn seed_remote(temp: &Path) -> (PathBuf, PathBuf, LocalRepositoryConfig) {
+        let bare = temp.join("remote.git");
+        let seed = temp.join("seed");
+        let work_root = temp.join("work");
+        fs::create_dir(&work_root).unwrap();
+
+        git(temp, &["init", "--bare", "-b", "main", "remote.git"]);
+        git(temp, &["clone", bare.to_str().unwrap(), "seed"]);
+        git(&seed, &["checkout", "-b", "main"]);
+        fs::write(seed.join("version.hpp"), "#define VERSION \"1.4.2\"\n").unwrap();
+        git(&seed, &["add", "version.hpp"]);
+        git(&seed, &["commit", "-m", "initial"]);
+        git(&seed, &["push", "origin", "main"]);
+
+        let repo_config = LocalRepositoryConfig {
+            name: "remote".to_string(),
+            remote_url: format!("file://{}", bare.display()),
+            ssh_key_path: None,
+            file_to_download: "version.hpp".to_string(),
+            pattern_version_to_search: "#define VERSION".to_string(),
+            branch_refs_to_observe: vec!["refs/heads/main".to_string()],
+            dry_run: false,
+        };
+        (bare, work_root, repo_config)
+    }
+
+    #[tokio::test]
+    async fn bumps_version_in_bare_repository() {
+        let temp = tempfile::tempdir().unwrap();
+        let (bare, work_root, repo_config) = seed_remote(temp.path());
+
+        let outcome = bump_local_repository(
+            "IncreaseAppVersion",
+            &repo_config,
+            "refs/heads/main",
+            &work_root,
+            false,
+        )
+        .await
+        .unwrap();
+        let DeliveryOutcome::Bumped(report) = outcome else {
+            panic!("expected a bump");
+        };
+        let content = git(&bare, &["show", "main:version.hpp"]);
+        assert_eq!(content, "#define VERSION \"1.5.2\"\n");
+        assert_eq!(
+            report.commit_sha.unwrap(),
+            git(&bare, &["rev-parse", "main"]).trim()
+        );
+
+        // The push done by the bot must not trigger another bump
+        let outcome = bump_local_repository(
+            "IncreaseAppVersion",
+            &repo_config,
+            "refs/heads/main",
+            &work_root,
+            false,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(outcome, DeliveryOutcome::BotSkip));
+        let content = git(&bare, &["show", "main:version.hpp"]);
+        assert_eq!(content, "#define VERSION \"1.5.2\"\n");
+    }
+
+    #[tokio::test]
+    async fn dry_run_of_a_repository_leaves_the_remote_unchanged() {
+        let temp = tempfile::tempdir().unwrap();
+        let (bare, work_root, mut repo_config) = seed_remote(temp.path());
+        repo_config.dry_run = true;
+        let head = git(&bare, &["rev-parse", "main"]);
+
+        let outcome = bump_local_repository(
+            "IncreaseAppVersion",
+            &repo_config,
+            "refs/heads/main",
+            &work_root,
+            false,
+        )
+        .await
+        .unwrap();
+        let DeliveryOutcome::Bumped(report) = outcome else {
+            panic!("expected a bump");
+        };
+        assert!(report.dry_run);
+        assert_eq!(report.commit_sha, None);
+        assert_eq!(report.commit_message, commit_message("1.5.2"));
+        assert!(report.diff.contains("-#define VERSION \"1.4.2\""));
+        assert!(report.diff.contains("+#define VERSION \"1.5.2\""));
+        assert_eq!(git(&bare, &["rev-parse", "main"]), head);
+    }
+
+    #[test]
+    fn quotes_the_key_path_for_the_shell() {
+        for path in ["/keys/deploy", "/keys/my key's; rm -rf ~", "$(id)`id`\\"] {
+            let output = StdCommand::new("sh")
+                .arg("-c")
+                .arg(format!("printf %s {}", shell_quote(path)))
+                .output()
+                .unwrap();
+            assert_eq!(String::from_utf8_lossy(&output.stdout), path);
+        }
+    }
+}
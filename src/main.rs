@@ -1,89 +1,162 @@
+mod admin;
 mod app_apis;
 mod app_config;
 mod app_errors;
+mod audit_log;
 mod callback_validator;
+mod cli;
+mod client_ip;
+mod dashboard;
+mod delivery_store;
+mod health;
+mod hook_subnets;
 mod installation_token_data;
+mod local_git;
+mod metrics;
+mod secrets;
+mod server;
+mod sqlite_storage;
+mod storage;
+mod telemetry;
+mod tls;
+mod token_cache;
+mod token_cipher;
+mod version_bump;
 mod webhook_data;
 mod worker;
 extern crate dotenv;
 use crate::{
-    app_apis::get_github_environment_details,
-    app_config::{create_app_folder, AppConfig, RepositoryConfig, WEBHOOK_COMMIT_TYPE_BOT},
-    worker::increase_version,
+    app_config::{
+        create_app_folder, AppConfig, ConfigSources, IpFilter, RepositoryConfig,
+        WEBHOOK_COMMIT_TYPE_BOT,
+    },
+    local_git::{bump_local_repository, get_local_repos_path},
+    webhook_data::{LocalPushHook, WebWebHook},
+    worker::{increase_version, DeliveryOutcome},
 };
-use anyhow::Result;
-use app_config::SecurityConfig;
+use anyhow::{bail, Result};
+use app_errors::AppErrors;
+use arc_swap::ArcSwap;
+use audit_log::{append_audit_entry, AuditEntry};
 use axum::{
     body::Bytes,
     extract::{ConnectInfo, Extension, Query, State},
     http::{HeaderMap, StatusCode},
-    routing::post,
+    routing::{get, post},
     Router,
 };
-use callback_validator::callback_validator;
+use callback_validator::{callback_validator, local_callback_validator};
+use clap::Parser;
+use cli::{run_command, Cli, Command};
 use core::panic;
+use delivery_store::{delivery_id, read_delivery, save_delivery, spawn_delivery_pruner};
 use dotenv::dotenv;
-use log::{error, info};
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use hook_subnets::{load_hook_subnets, spawn_hook_subnets_refresher, SharedSecurityConfig};
+use installation_token_data::{clean_up_installation_data, TokenCleanup};
+use metrics::{
+    error_outcome, event_label, is_unverified, metrics_endpoint, record_delivery, InProgressGuard,
+    UNVERIFIED_EVENT,
+};
+use secrets::resolve_secrets;
+use server::serve_connections;
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc};
+use storage::init_storage;
+use telemetry::{
+    delivery_span, init_tracing, local_delivery_id, local_delivery_span, record_delivery_target,
+};
+use tls::{load_server_config, spawn_certificate_reloader, SharedServerConfig};
+use token_cipher::TokenCipher;
 use tokio::net::TcpListener;
+use tracing::{error, info, warn, Instrument, Span};
 
-struct SimpleLogger;
-
-impl log::Log for SimpleLogger {
-    fn enabled(&self, metadata: &log::Metadata) -> bool {
-        metadata.level() <= log::Level::Info
-    }
-
-    fn log(&self, record: &log::Record) {
-        if self.enabled(record.metadata()) {
-            println!("{} - {}", record.level(), record.args());
+#[tokio::main]
+async fn main() {
+    // Loaded before parsing so `.env` can also provide the values of the command line flags
+    dotenv().ok();
+    let cli = Cli::parse();
+    match cli.command {
+        None | Some(Command::Serve) => serve(cli.data_dir, cli.config.sources()).await,
+        Some(command) => {
+            std::process::exit(run_command(command, cli.data_dir, cli.config.sources()).await)
         }
     }
-
-    fn flush(&self) {}
-}
-
-static LOGGER: SimpleLogger = SimpleLogger;
-
-pub fn init_logger() -> Result<(), log::SetLoggerError> {
-    log::set_logger(&LOGGER).map(|()| log::set_max_level(log::LevelFilter::Info))
 }
 
-#[tokio::main]
-async fn main() {
+async fn serve(data_dir: Option<PathBuf>, sources: ConfigSources) {
     //TODO: follow best practices https://docs.github.com/en/webhooks/using-webhooks/best-practices-for-using-webhooks
-    dotenv().ok();
-    let app_config_res = AppConfig::new();
+    init_tracing().unwrap();
+    if let Err(err) = init_storage(data_dir) {
+        panic!("Failed to open storage: {err}");
+    }
+    let app_config_res = AppConfig::new(&sources);
     if let Err(err) = app_config_res {
         let err_string = err.to_string();
         panic!("Invalid environment variables: {err_string}");
     }
-    let app_config = app_config_res.unwrap();
+    let mut app_config = app_config_res.unwrap();
+    if let Err(err) = resolve_secrets(&mut app_config).await {
+        panic!("Failed to load secrets: {err}");
+    }
+    if let Err(err) = app_config.validate() {
+        panic!("{err}");
+    }
+    if app_config.token_encryption_key.is_none() {
+        warn!("No token_encryption_key set, installation tokens are stored in plaintext");
+    }
+    let proxy_protocol = app_config.proxy_protocol;
+    let ip_filter = match IpFilter::new(&app_config) {
+        Ok(ip_filter) => Arc::new(ip_filter),
+        Err(err) => panic!("Invalid IP lists: {err}"),
+    };
 
     if let Err(err) = create_app_folder() {
         panic!("Failed to create app folders: {err}");
     }
+    match clean_up_installation_data(TokenCipher::from_config(&app_config).as_ref()) {
+        Ok(cleanup) if cleanup != TokenCleanup::default() => info!(
+            "Removed {} installation files of older versions, encrypted {} plaintext ones",
+            cleanup.removed_legacy, cleanup.encrypted
+        ),
+        Ok(_) => {}
+        Err(err) => warn!("Failed to clean up the installation files: {err}"),
+    }
 
-    //TODO: replace log with trace
-    init_logger().unwrap();
+    let tls: Option<SharedServerConfig> = app_config.tls.clone().map(|tls_config| {
+        let server_config = match load_server_config(&tls_config) {
+            Ok(server_config) => server_config,
+            Err(err) => panic!("Invalid TLS config: {err:#}"),
+        };
+        let shared = Arc::new(ArcSwap::from_pointee(server_config));
+        spawn_certificate_reloader(shared.clone(), tls_config);
+        shared
+    });
 
-    let security_details = get_github_environment_details().await;
-    if let Err(err) = security_details {
-        error!("failed to obtain security settings: {err}");
-        return;
-    }
-    let security_details = Arc::new(security_details.unwrap());
+    let security_details: SharedSecurityConfig =
+        Arc::new(ArcSwap::from_pointee(load_hook_subnets().await));
+    spawn_hook_subnets_refresher(
+        security_details.clone(),
+        app_config.hook_subnets_refresh_minutes,
+    );
+    spawn_delivery_pruner(app_config.delivery_retention_days);
 
+    // The IP allowlist is only enforced by the callback handlers, probes can reach the rest
     let app = Router::new()
         .route("/callback", post(callback_entrypoint))
+        .route("/git/callback", post(local_callback_entrypoint))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .route("/version", get(health::version))
+        .route("/metrics", get(metrics_endpoint))
+        .nest("/admin", admin::admin_router(app_config.clone()))
         .with_state(app_config)
         .layer(Extension(security_details))
-        .into_make_service_with_connect_info::<SocketAddr>();
+        .layer(Extension(ip_filter.clone()));
 
     let addr = "0.0.0.0:3000";
-    info!("Started listening on addr: {addr}");
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    info!("Started listening on addr: {addr} ({scheme})");
     let listener = TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    serve_connections(listener, app, ip_filter, proxy_protocol, tls).await;
 }
 
 async fn callback_entrypoint_impl(
@@ -91,56 +164,197 @@ async fn callback_entrypoint_impl(
     params: HashMap<String, String>,
     headers: HeaderMap,
     payload: Bytes,
-) -> Result<()> {
+    replay: bool,
+) -> Result<DeliveryOutcome> {
     info!("Got a callback!");
-    let webhook = callback_validator(&app_config, params, headers, payload).await?;
+    let webhook = callback_validator(&app_config, params, headers.clone(), payload.clone()).await?;
+    record_delivery_target(&webhook.repository.full_name, webhook.installation.id);
 
-    let file_name = format!("{}.json", webhook.installation.id);
-    let repo_config = RepositoryConfig::new(file_name.as_str(), &app_config)?;
+    if !replay {
+        if let Err(err) = save_delivery(&headers, &payload) {
+            error!("Failed to store delivery: {err}");
+        }
+    }
+
+    let mut audit_entry = AuditEntry::from_webhook(delivery_id(&headers), &webhook);
+    audit_entry.replay = replay;
+    let result = process_webhook(&app_config, webhook).await;
+    audit_entry.record_result(&result);
+    if let Err(err) = append_audit_entry(&audit_entry) {
+        error!("Failed to write audit entry: {err}");
+    }
+    result
+}
+
+async fn process_webhook(app_config: &AppConfig, webhook: WebWebHook) -> Result<DeliveryOutcome> {
+    let repo_config = RepositoryConfig::new(webhook.installation.id, app_config)?;
 
     if !repo_config.branch_refs_to_observe.contains(&webhook.ref_) {
         let found_ref = webhook.ref_;
         info!("Found other ref \"{found_ref}\" than observed one, will stop!");
-        return Ok(());
+        return Ok(DeliveryOutcome::IgnoredRef);
     }
 
     if webhook.sender.type_ == WEBHOOK_COMMIT_TYPE_BOT {
         if !repo_config.commit_when_sender_is_bot {
             info!("Found restriction onyl to commit when the sender is User, will stop here!");
-            return Ok(());
+            return Ok(DeliveryOutcome::BotSkip);
         }
 
         if webhook.sender.login == app_config.app_name {
             info!("The last commit was made by this bot, will ignore that one!");
-            return Ok(());
+            return Ok(DeliveryOutcome::BotSkip);
         }
     }
 
-    increase_version(&app_config, &repo_config, webhook).await?;
+    let report = increase_version(app_config, &repo_config, webhook).await?;
 
     info!("ALL GOOD");
-    Ok(())
+    Ok(DeliveryOutcome::Bumped(report))
+}
+
+async fn replay_delivery(app_config: AppConfig, delivery_id: &str) -> Result<DeliveryOutcome> {
+    let delivery = read_delivery(delivery_id)?;
+    let headers = delivery.header_map()?;
+    let span = delivery_span(&headers);
+    info!("Replaying delivery {delivery_id}");
+    callback_entrypoint_impl(
+        app_config,
+        HashMap::new(),
+        headers,
+        delivery.payload_bytes(),
+        true,
+    )
+    .instrument(span)
+    .await
 }
 
 async fn callback_entrypoint(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     State(env_vars): State<AppConfig>,
-    Extension(security): Extension<Arc<SecurityConfig>>,
+    Extension(security): Extension<SharedSecurityConfig>,
+    Extension(ip_filter): Extension<Arc<IpFilter>>,
     Query(params): Query<HashMap<String, String>>,
     headers: HeaderMap,
     payload: Bytes,
 ) -> (StatusCode, String) {
-    if !env_vars.whitelist_ips.contains(&addr.ip().to_string()) && !security.contains(addr.ip()) {
-        error!(
-            "Invalid ip {} conenected, will be blocked!",
-            addr.ip().to_string()
-        );
+    let event = event_label(&headers);
+    let ip = ip_filter.client_ip(addr.ip(), &headers);
+    if ip_filter.is_denied(ip) || !(ip_filter.is_allowed(ip) || security.load().contains(ip)) {
+        error!("Invalid ip {ip} conenected, will be blocked!");
+        record_delivery(UNVERIFIED_EVENT, "forbidden_ip");
         return (StatusCode::FORBIDDEN, "Invalid".to_string());
     }
-    match callback_entrypoint_impl(env_vars, params, headers, payload).await {
-        Ok(()) => (StatusCode::OK, "OK".to_string()),
+
+    let _in_progress = InProgressGuard::enter();
+    let span = delivery_span(&headers);
+    let result = callback_entrypoint_impl(env_vars, params, headers, payload, false)
+        .instrument(span)
+        .await;
+    match result {
+        Ok(outcome) => {
+            record_delivery(event, outcome.metric_label());
+            match outcome {
+                DeliveryOutcome::Bumped(report) if report.dry_run => {
+                    (StatusCode::OK, report.describe())
+                }
+                _ => (StatusCode::OK, "OK".to_string()),
+            }
+        }
+        Err(err) => {
+            info!("Failed: {}", err);
+            let event = match is_unverified(&err) {
+                true => UNVERIFIED_EVENT,
+                false => event,
+            };
+            record_delivery(event, error_outcome(&err));
+            (StatusCode::BAD_REQUEST, err.to_string())
+        }
+    }
+}
+
+async fn local_callback_entrypoint_impl(
+    app_config: AppConfig,
+    headers: HeaderMap,
+    payload: Bytes,
+) -> Result<DeliveryOutcome> {
+    info!("Got a local git callback!");
+    let hook = local_callback_validator(&app_config, headers, payload).await?;
+    Span::current().record("repository", hook.repository.as_str());
+
+    let mut audit_entry = AuditEntry::from_local_hook(&hook);
+    let result = process_local_hook(&app_config, hook).await;
+    audit_entry.record_result(&result);
+    if let Err(err) = append_audit_entry(&audit_entry) {
+        error!("Failed to write audit entry: {err}");
+    }
+    result
+}
+
+async fn process_local_hook(
+    app_config: &AppConfig,
+    hook: LocalPushHook,
+) -> Result<DeliveryOutcome> {
+    let Some(repo_config) = app_config
+        .local_repositories
+        .iter()
+        .find(|repo| repo.name == hook.repository)
+    else {
+        bail!(AppErrors::UnknownLocalRepository(hook.repository));
+    };
+
+    if !repo_config.branch_refs_to_observe.contains(&hook.ref_) {
+        let found_ref = hook.ref_;
+        info!("Found other ref \"{found_ref}\" than observed one, will stop!");
+        return Ok(DeliveryOutcome::IgnoredRef);
+    }
+
+    let outcome = bump_local_repository(
+        &app_config.app_name,
+        repo_config,
+        &hook.ref_,
+        &get_local_repos_path(),
+        app_config.dry_run,
+    )
+    .await?;
+
+    info!("ALL GOOD");
+    Ok(outcome)
+}
+
+// Plain git servers are not part of the GitHub hook subnets, only the whitelist applies
+async fn local_callback_entrypoint(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(env_vars): State<AppConfig>,
+    Extension(ip_filter): Extension<Arc<IpFilter>>,
+    headers: HeaderMap,
+    payload: Bytes,
+) -> (StatusCode, String) {
+    let ip = ip_filter.client_ip(addr.ip(), &headers);
+    if !ip_filter.is_allowed(ip) {
+        error!("Invalid ip {ip} conenected, will be blocked!");
+        record_delivery("git", "forbidden_ip");
+        return (StatusCode::FORBIDDEN, "Invalid".to_string());
+    }
+
+    let _in_progress = InProgressGuard::enter();
+    let span = local_delivery_span(&local_delivery_id());
+    let result = local_callback_entrypoint_impl(env_vars, headers, payload)
+        .instrument(span)
+        .await;
+    match result {
+        Ok(outcome) => {
+            record_delivery("git", outcome.metric_label());
+            match outcome {
+                DeliveryOutcome::Bumped(report) if report.dry_run => {
+                    (StatusCode::OK, report.describe())
+                }
+                _ => (StatusCode::OK, "OK".to_string()),
+            }
+        }
         Err(err) => {
-            info!("Failed: {}", err.to_string());
+            info!("Failed: {}", err);
+            record_delivery("git", error_outcome(&err));
             (StatusCode::BAD_REQUEST, err.to_string())
         }
     }
@@ -0,0 +1,208 @@
+use std::sync::LazyLock;
+
+use anyhow::Error;
+use axum::http::{header::CONTENT_TYPE, HeaderMap, HeaderValue, StatusCode};
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, Encoder, HistogramVec,
+    IntCounterVec, IntGauge, TextEncoder,
+};
+
+use crate::app_errors::AppErrors;
+
+pub static DELIVERIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "increase_version_deliveries_total",
+        "Webhook deliveries by event and outcome",
+        &["event", "outcome"]
+    )
+    .expect("valid deliveries metric")
+});
+
+pub static API_CALL_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "increase_version_github_api_duration_seconds",
+        "Duration of the GitHub API calls",
+        &["call"]
+    )
+    .expect("valid api duration metric")
+});
+
+pub static TOKEN_CACHE: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "increase_version_token_cache_total",
+        "Installation token lookups by result",
+        &["result"]
+    )
+    .expect("valid token cache metric")
+});
+
+pub static SECRET_MATCHES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "increase_version_webhook_secret_matches_total",
+        "Verified deliveries by the webhook secret that matched",
+        &["secret"]
+    )
+    .expect("valid secret matches metric")
+});
+
+pub static QUEUE_DEPTH: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "increase_version_deliveries_in_progress",
+        "Deliveries currently being processed"
+    )
+    .expect("valid queue depth metric")
+});
+
+// Counts a delivery in `QUEUE_DEPTH` until dropped, even when the request future is cancelled
+pub struct InProgressGuard;
+
+impl InProgressGuard {
+    pub fn enter() -> InProgressGuard {
+        QUEUE_DEPTH.inc();
+        InProgressGuard
+    }
+}
+
+impl Drop for InProgressGuard {
+    fn drop(&mut self) {
+        QUEUE_DEPTH.dec();
+    }
+}
+
+pub fn record_delivery(event: &str, outcome: &str) {
+    DELIVERIES.with_label_values(&[event, outcome]).inc();
+}
+
+pub fn record_token_lookup(hit: bool) {
+    let result = match hit {
+        true => "hit",
+        false => "miss",
+    };
+    TOKEN_CACHE.with_label_values(&[result]).inc();
+}
+
+// Outcome label of a failed delivery, named after the `AppErrors` variant when there is one
+pub fn error_outcome(err: &Error) -> &'static str {
+    match err.downcast_ref::<AppErrors>() {
+        Some(app_error) => app_error.variant_name(),
+        None => "internal_error",
+    }
+}
+
+// Event label of a delivery whose signature was not verified, its headers cannot be trusted
+pub static UNVERIFIED_EVENT: &str = "unknown";
+
+// Any client can send `X-GitHub-Event`, the label is limited to a fixed set so the number of
+// series stays bounded
+pub fn event_label(headers: &HeaderMap) -> &'static str {
+    match headers
+        .get("X-GitHub-Event")
+        .and_then(|value| value.to_str().ok())
+    {
+        Some("push") => "push",
+        Some("ping") => "ping",
+        _ => "other",
+    }
+}
+
+// Rejected by the validator before the signature was verified
+pub fn is_unverified(err: &Error) -> bool {
+    matches!(
+        err.downcast_ref::<AppErrors>(),
+        Some(
+            AppErrors::TooManyQueryParams(..)
+                | AppErrors::MissingHeader(..)
+                | AppErrors::HeaderInvalidFormatError(..)
+                | AppErrors::HeaderParsingError(..)
+                | AppErrors::SignatureError(..)
+        )
+    )
+}
+
+pub async fn metrics_endpoint() -> (StatusCode, HeaderMap, Vec<u8>) {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    let mut headers = HeaderMap::new();
+    if let Err(err) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            headers,
+            err.to_string().into_bytes(),
+        );
+    }
+    if let Ok(content_type) = HeaderValue::from_str(encoder.format_type()) {
+        headers.insert(CONTENT_TYPE, content_type);
+    }
+    (StatusCode::OK, headers, buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_progress_guard_decrements_when_dropped() {
+        let before = QUEUE_DEPTH.get();
+        let guard = InProgressGuard::enter();
+        assert_eq!(QUEUE_DEPTH.get(), before + 1);
+        drop(guard);
+        assert_eq!(QUEUE_DEPTH.get(), before);
+    }
+
+    #[test]
+    fn keeps_the_event_labels_bounded() {
+        for index in 0..100 {
+            let mut headers = HeaderMap::new();
+            let event = HeaderValue::from_str(&format!("random-event-{index}")).unwrap();
+            headers.insert("X-GitHub-Event", event);
+            record_delivery(event_label(&headers), "bumped");
+        }
+        let mut headers = HeaderMap::new();
+        headers.insert("X-GitHub-Event", HeaderValue::from_static("ping"));
+        assert_eq!(event_label(&headers), "ping");
+        assert_eq!(event_label(&HeaderMap::new()), "other");
+
+        let families = prometheus::gather();
+        let deliveries = families
+            .iter()
+            .find(|family| family.name() == "increase_version_deliveries_total")
+            .unwrap();
+        for metric in deliveries.get_metric() {
+            let event = metric
+                .get_label()
+                .iter()
+                .find(|label| label.name() == "event")
+                .unwrap();
+            assert!(["push", "ping", "other", "unknown", "git"].contains(&event.value()));
+        }
+    }
+
+    #[test]
+    fn treats_validator_rejections_as_unverified() {
+        assert!(is_unverified(&AppErrors::SignatureError("mismatch").into()));
+        assert!(is_unverified(
+            &AppErrors::MissingHeader("X-GitHub-Event").into()
+        ));
+        assert!(!is_unverified(&AppErrors::InvalidPayload().into()));
+    }
+
+    #[tokio::test]
+    async fn exposes_deliveries_by_outcome() {
+        record_delivery("push", "bumped");
+        record_delivery("push", "signature_mismatch");
+
+        let (status, headers, body) = metrics_endpoint().await;
+        let body = String::from_utf8(body).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers[CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+        assert!(
+            body.contains("increase_version_deliveries_total{event=\"push\",outcome=\"bumped\"}")
+        );
+        assert!(body.contains(
+            "increase_version_deliveries_total{event=\"push\",outcome=\"signature_mismatch\"}"
+        ));
+    }
+}
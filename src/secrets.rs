@@ -0,0 +1,360 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::OnceCell;
+use tracing::info;
+
+use crate::{
+    app_config::{AppConfig, CallbackSecret},
+    app_errors::AppErrors,
+};
+
+// Secrets of the app config and the environment variable each one is read from,
+// `previous_callback_tokens` holds the JSON list of the config file
+static SECRETS: [(&str, &str); 5] = [
+    ("callback_token", "CALLBACK_SECRET_TOKEN"),
+    (
+        "previous_callback_tokens",
+        "PREVIOUS_CALLBACK_SECRET_TOKENS",
+    ),
+    ("private_key", "PRIVATE_KEY"),
+    ("admin_token", "ADMIN_TOKEN"),
+    ("token_encryption_key", "TOKEN_ENCRYPTION_KEY"),
+];
+
+static VAULT_TOKEN_VAR: &str = "VAULT_TOKEN";
+// Startup waits for Vault, an unreachable one has to fail it instead of blocking it
+static VAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+static VAULT_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    reqwest::Client::builder()
+        .timeout(VAULT_TIMEOUT)
+        .build()
+        .expect("Failed to build the vault client")
+});
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SecretProviderConfig {
+    #[default]
+    Env,
+    // One file per secret, named after the secret, as mounted by Docker or Kubernetes
+    File {
+        directory: String,
+    },
+    // KV version 2 secret engine, the token is read from `VAULT_TOKEN` or `VAULT_TOKEN_FILE`
+    Vault {
+        address: String,
+        #[serde(default = "default_vault_mount")]
+        mount: String,
+        path: String,
+    },
+}
+
+fn default_vault_mount() -> String {
+    "secret".to_string()
+}
+
+pub trait SecretProvider {
+    async fn fetch(&self, key: &str) -> Result<Option<String>>;
+}
+
+fn read_secret_file(path: &Path, source: &str) -> Result<String> {
+    match fs::read_to_string(path) {
+        // Editors and `echo` leave a trailing newline that is not part of the secret
+        Ok(value) => Ok(value.trim_end_matches(['\r', '\n']).to_string()),
+        Err(err) => bail!(AppErrors::SecretUnavailable(format!(
+            "{source} {}: {err}",
+            path.display()
+        ))),
+    }
+}
+
+// `NAME`, or the content of the file at `NAME_FILE`
+pub fn env_or_file(var: &str) -> Result<Option<String>> {
+    lookup_or_file(var, |var| env::var(var).ok())
+}
+
+// `env_or_file` reading the variables through `lookup`
+pub fn lookup_or_file(
+    var: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<Option<String>> {
+    if let Some(value) = lookup(var) {
+        return Ok(Some(value));
+    }
+    let file_var = format!("{var}_FILE");
+    let Some(path) = lookup(&file_var) else {
+        return Ok(None);
+    };
+    Ok(Some(read_secret_file(Path::new(&path), &file_var)?))
+}
+
+pub struct EnvSecrets;
+
+impl SecretProvider for EnvSecrets {
+    async fn fetch(&self, key: &str) -> Result<Option<String>> {
+        let Some((_, var)) = SECRETS.iter().find(|(name, _)| *name == key) else {
+            return Ok(None);
+        };
+        env_or_file(var)
+    }
+}
+
+pub struct FileSecrets {
+    pub directory: PathBuf,
+}
+
+impl SecretProvider for FileSecrets {
+    async fn fetch(&self, key: &str) -> Result<Option<String>> {
+        let path = self.directory.join(key);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(read_secret_file(&path, "secret file")?))
+    }
+}
+
+pub struct VaultSecrets {
+    pub url: String,
+    pub token: String,
+    // The secret is downloaded by the first `fetch`, each resolve creates a new provider
+    data: OnceCell<Value>,
+}
+
+impl VaultSecrets {
+    pub fn new(address: &str, mount: &str, path: &str) -> Result<VaultSecrets> {
+        let Some(token) = env_or_file(VAULT_TOKEN_VAR)? else {
+            bail!(AppErrors::SecretUnavailable(format!(
+                "{VAULT_TOKEN_VAR} is not set"
+            )));
+        };
+        Ok(VaultSecrets {
+            url: format!("{}/v1/{mount}/data/{path}", address.trim_end_matches('/')),
+            token,
+            data: OnceCell::new(),
+        })
+    }
+
+    // The key/value pairs of the secret, `Null` when it does not exist
+    async fn read_secret(&self) -> Result<Value> {
+        let response = VAULT_CLIENT
+            .get(&self.url)
+            .header("X-Vault-Token", &self.token)
+            .send()
+            .await;
+        let response = match response {
+            Ok(response) if response.status() == StatusCode::NOT_FOUND => return Ok(Value::Null),
+            Ok(response) => response,
+            Err(err) => bail!(AppErrors::SecretUnavailable(format!("vault: {err}"))),
+        };
+        if !response.status().is_success() {
+            bail!(AppErrors::SecretUnavailable(format!(
+                "vault answered {}",
+                response.status()
+            )));
+        }
+        let mut body: Value = response.json().await?;
+        Ok(body["data"]["data"].take())
+    }
+}
+
+impl SecretProvider for VaultSecrets {
+    async fn fetch(&self, key: &str) -> Result<Option<String>> {
+        let data = self.data.get_or_try_init(|| self.read_secret()).await?;
+        Ok(data[key].as_str().map(str::to_string))
+    }
+}
+
+async fn apply_secrets<P: SecretProvider>(provider: &P, app_config: &mut AppConfig) -> Result<()> {
+    for (key, _) in SECRETS {
+        let Some(value) = provider.fetch(key).await? else {
+            continue;
+        };
+        match key {
+            "callback_token" => app_config.callback_token = value,
+            "previous_callback_tokens" => {
+                app_config.previous_callback_tokens =
+                    serde_json::from_str::<Vec<CallbackSecret>>(&value).map_err(|err| {
+                        AppErrors::SecretUnavailable(format!("previous_callback_tokens: {err}"))
+                    })?
+            }
+            "private_key" => app_config.private_signature = value,
+            "admin_token" => app_config.admin_token = Some(value),
+            "token_encryption_key" => app_config.token_encryption_key = Some(value),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Secrets found by the provider replace the ones of the config file and of the environment
+pub async fn resolve_secrets(app_config: &mut AppConfig) -> Result<()> {
+    match app_config.secret_provider.clone() {
+        SecretProviderConfig::Env => apply_secrets(&EnvSecrets, app_config).await,
+        SecretProviderConfig::File { directory } => {
+            info!("Reading secrets from {directory}");
+            let provider = FileSecrets {
+                directory: PathBuf::from(directory),
+            };
+            apply_secrets(&provider, app_config).await
+        }
+        SecretProviderConfig::Vault {
+            address,
+            mount,
+            path,
+        } => {
+            info!("Reading secrets from vault {address} {mount}/{path}");
+            let provider = VaultSecrets::new(&address, &mount, &path)?;
+            apply_secrets(&provider, app_config).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::Path as UrlPath, http::HeaderMap, routing::get, Json, Router};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tempfile::TempDir;
+    use tokio::net::TcpListener;
+
+    static VAULT_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+    // Answers like the KV version 2 engine of Vault for `secret/increase-version`
+    async fn vault_stub(
+        UrlPath(path): UrlPath<String>,
+        headers: HeaderMap,
+    ) -> (StatusCode, Json<Value>) {
+        VAULT_REQUESTS.fetch_add(1, Ordering::SeqCst);
+        if headers
+            .get("X-Vault-Token")
+            .is_none_or(|token| token != "stub-token")
+        {
+            return (
+                StatusCode::FORBIDDEN,
+                Json(json!({ "errors": ["permission denied"] })),
+            );
+        }
+        if path != "increase-version" {
+            return (StatusCode::NOT_FOUND, Json(json!({ "errors": [] })));
+        }
+        let data = json!({
+            "callback_token": "from vault",
+            "admin_token": "vault admin",
+            "previous_callback_tokens": r#"[{ "name": "2025", "token": "old vault" }]"#,
+        });
+        (
+            StatusCode::OK,
+            Json(json!({ "data": { "data": data, "metadata": { "version": 3 } } })),
+        )
+    }
+
+    async fn start_vault_stub() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let app = Router::new().route("/v1/secret/data/*path", get(vault_stub));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        address
+    }
+
+    #[tokio::test]
+    async fn reads_secrets_from_vault() {
+        let address = start_vault_stub().await;
+        let url = format!("{address}/v1/secret/data/increase-version");
+        let provider = VaultSecrets {
+            url: url.clone(),
+            token: "stub-token".to_string(),
+            data: OnceCell::new(),
+        };
+        assert_eq!(
+            provider.fetch("callback_token").await.unwrap().as_deref(),
+            Some("from vault")
+        );
+        assert_eq!(provider.fetch("private_key").await.unwrap(), None);
+
+        let mut app_config = AppConfig::default();
+        apply_secrets(&provider, &mut app_config).await.unwrap();
+        assert_eq!(app_config.callback_token, "from vault");
+        assert_eq!(app_config.admin_token.as_deref(), Some("vault admin"));
+        assert_eq!(app_config.previous_callback_tokens[0].token, "old vault");
+        assert_eq!(VAULT_REQUESTS.load(Ordering::SeqCst), 1);
+
+        let provider = VaultSecrets {
+            url,
+            token: "wrong".to_string(),
+            data: OnceCell::new(),
+        };
+        assert!(provider.fetch("callback_token").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_hanging_vault() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        // Accepts the connection but never answers
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await
+        });
+        let provider = VaultSecrets {
+            url: format!("http://{address}/v1/secret/data/increase-version"),
+            token: "stub-token".to_string(),
+            data: OnceCell::new(),
+        };
+        let fetch = provider.fetch("callback_token");
+        let result = tokio::time::timeout(VAULT_TIMEOUT + Duration::from_secs(5), fetch).await;
+        assert!(result.expect("the vault request is not bounded").is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_secrets_from_files() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("callback_token"), "from file\n").unwrap();
+        fs::write(
+            dir.path().join("previous_callback_tokens"),
+            r#"[{ "name": "2025", "token": "old file", "expires_at": "2026-11-01T00:00:00Z" }]"#,
+        )
+        .unwrap();
+        let provider = FileSecrets {
+            directory: dir.path().to_path_buf(),
+        };
+        let mut app_config = AppConfig {
+            callback_token: "from config".to_string(),
+            private_signature: "config key".to_string(),
+            ..AppConfig::default()
+        };
+        apply_secrets(&provider, &mut app_config).await.unwrap();
+        assert_eq!(app_config.callback_token, "from file");
+        assert_eq!(app_config.private_signature, "config key");
+        assert_eq!(app_config.previous_callback_tokens[0].name, "2025");
+        assert_eq!(app_config.previous_callback_tokens[0].token, "old file");
+
+        fs::write(dir.path().join("previous_callback_tokens"), "old file").unwrap();
+        assert!(apply_secrets(&provider, &mut app_config).await.is_err());
+    }
+
+    #[test]
+    fn never_serializes_secrets() {
+        let app_config = AppConfig {
+            callback_token: "callback secret".to_string(),
+            private_signature: "private key".to_string(),
+            admin_token: Some("admin secret".to_string()),
+            ..AppConfig::default()
+        };
+        let data = serde_json::to_string(&app_config).unwrap();
+        for secret in ["callback secret", "private key", "admin secret"] {
+            assert!(!data.contains(secret));
+        }
+        assert!(serde_json::from_str::<AppConfig>(&data).is_ok());
+    }
+}
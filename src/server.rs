@@ -0,0 +1,102 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use axum::{extract::ConnectInfo, http::Request, Router};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+    service::TowerToHyperService,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+use tracing::{debug, error, warn};
+
+use crate::{
+    app_config::IpFilter,
+    client_ip::read_proxy_header,
+    tls::{SharedServerConfig, TLS_HANDSHAKE_TIMEOUT},
+};
+
+// Pause after a failed `accept()`, errors like EMFILE would otherwise spin the loop
+static ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+// Request extension set when the TLS client sent a certificate signed by `client_ca_path`
+#[derive(Clone, Copy)]
+pub struct ClientCertificate;
+
+async fn serve_stream<S>(stream: S, app: Router, remote: SocketAddr, client_certificate: bool)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = app.map_request(move |mut request: Request<Incoming>| {
+        request
+            .extensions_mut()
+            .insert(ConnectInfo::<SocketAddr>(remote));
+        if client_certificate {
+            request.extensions_mut().insert(ClientCertificate);
+        }
+        request
+    });
+    if let Err(err) = Builder::new(TokioExecutor::new())
+        .serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(service))
+        .await
+    {
+        debug!("Connection from {remote} closed with an error: {err}");
+    }
+}
+
+// Accept loop replacing `axum::serve` so the PROXY protocol header can be read and TLS terminated
+// before HTTP starts, handlers still get the client address through `ConnectInfo<SocketAddr>`
+pub async fn serve_connections(
+    listener: TcpListener,
+    app: Router,
+    ip_filter: Arc<IpFilter>,
+    proxy_protocol: bool,
+    tls: Option<SharedServerConfig>,
+) {
+    loop {
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(err) => {
+                error!("Failed to accept connection: {err}");
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+        let app = app.clone();
+        let ip_filter = ip_filter.clone();
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            let mut remote = peer;
+            if proxy_protocol && ip_filter.is_trusted_proxy(peer.ip()) {
+                match read_proxy_header(&mut stream).await {
+                    Ok(Some(source)) => remote = source,
+                    Ok(None) => {}
+                    Err(err) => {
+                        warn!("Dropping connection from {peer}, invalid PROXY header: {err}");
+                        return;
+                    }
+                }
+            }
+
+            let Some(tls) = tls else {
+                serve_stream(stream, app, remote, false).await;
+                return;
+            };
+            // The current config is loaded per connection so renewed certificates apply right away
+            let handshake = TlsAcceptor::from(tls.load_full()).accept(stream);
+            match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, handshake).await {
+                Ok(Ok(stream)) => {
+                    let client_certificate = stream.get_ref().1.peer_certificates().is_some();
+                    serve_stream(stream, app, remote, client_certificate).await;
+                }
+                Ok(Err(err)) => debug!("TLS handshake with {remote} failed: {err}"),
+                Err(_) => debug!("TLS handshake with {remote} timed out"),
+            }
+        });
+    }
+}
@@ -0,0 +1,436 @@
+use std::{fs, path::Path, sync::Mutex};
+
+use anyhow::{ensure, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use tracing::info;
+
+use crate::{
+    app_errors::AppErrors,
+    storage::{import_storage, ImportSummary, Storage},
+};
+
+// Applied in order, `PRAGMA user_version` holds the number of migrations already run
+static MIGRATIONS: [&str; 1] = ["
+    CREATE TABLE app_config (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        data TEXT NOT NULL
+    );
+    CREATE TABLE repository_configs (
+        installation_id TEXT PRIMARY KEY,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE tokens (
+        key TEXT PRIMARY KEY,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE deliveries (
+        delivery_id TEXT PRIMARY KEY,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE audit_entries (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE cache_entries (
+        key TEXT PRIMARY KEY,
+        data TEXT NOT NULL
+    );
+"];
+
+pub struct SqliteStorage {
+    connection: Mutex<Connection>,
+    path: String,
+}
+
+fn migrate(connection: &mut Connection) -> Result<()> {
+    let version: i64 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        info!("Applying database migration {}", index + 1);
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", index as i64 + 1)?;
+        transaction.commit()?;
+    }
+    Ok(())
+}
+
+// Sets mode 0600 on `path`, creating it first when `create` is set
+fn restrict_permissions(path: &Path, create: bool) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        if create {
+            fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .mode(0o600)
+                .open(path)?;
+        }
+        if path.exists() {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (path, create);
+    Ok(())
+}
+
+impl SqliteStorage {
+    pub fn open(path: &Path) -> Result<SqliteStorage> {
+        // The database and its WAL hold the installation tokens, the file is created readable by
+        // its owner only before SQLite opens it
+        restrict_permissions(path, true)?;
+        let mut connection = Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        for suffix in ["-wal", "-shm"] {
+            let mut sidecar = path.as_os_str().to_owned();
+            sidecar.push(suffix);
+            restrict_permissions(Path::new(&sidecar), false)?;
+        }
+        connection.busy_timeout(std::time::Duration::from_secs(5))?;
+        migrate(&mut connection)?;
+        Ok(SqliteStorage {
+            connection: Mutex::new(connection),
+            path: path.display().to_string(),
+        })
+    }
+
+    // Imports `source` in one transaction, nothing is kept when a record fails. Audit entries are
+    // appended, a second import would duplicate them unless `force` is set
+    pub fn import(&self, source: &dyn Storage, force: bool) -> Result<ImportSummary> {
+        self.in_transaction(|| {
+            let has_audit_entries: bool = self.with_connection(|connection| {
+                Ok(connection.query_row(
+                    "SELECT EXISTS (SELECT 1 FROM audit_entries)",
+                    [],
+                    |row| row.get(0),
+                )?)
+            })?;
+            ensure!(
+                force || !has_audit_entries,
+                AppErrors::ImportTargetNotEmpty(self.path.clone())
+            );
+            import_storage(source, self)
+        })
+    }
+
+    // Every statement of `action` goes through the connection lock on its own, so this is only
+    // meant for single threaded tools like `import`
+    fn in_transaction<T>(&self, action: impl FnOnce() -> Result<T>) -> Result<T> {
+        self.with_connection(|connection| Ok(connection.execute_batch("BEGIN IMMEDIATE")?))?;
+        match action() {
+            Ok(value) => {
+                self.with_connection(|connection| Ok(connection.execute_batch("COMMIT")?))?;
+                Ok(value)
+            }
+            Err(err) => {
+                let _ =
+                    self.with_connection(|connection| Ok(connection.execute_batch("ROLLBACK")?));
+                Err(err)
+            }
+        }
+    }
+
+    fn with_connection<T>(&self, action: impl FnOnce(&Connection) -> Result<T>) -> Result<T> {
+        let connection = self.connection.lock().expect("database lock poisoned");
+        action(&connection)
+    }
+
+    fn read_data(&self, query: &str, key: &str) -> Result<Option<String>> {
+        self.with_connection(|connection| {
+            Ok(connection
+                .query_row(query, [key], |row| row.get(0))
+                .optional()?)
+        })
+    }
+
+    fn write_data(&self, query: &str, key: &str, data: &str) -> Result<()> {
+        self.with_connection(|connection| {
+            connection.execute(query, [key, data])?;
+            Ok(())
+        })
+    }
+
+    fn read_column(&self, query: &str) -> Result<Vec<String>> {
+        self.with_connection(|connection| {
+            let mut statement = connection.prepare(query)?;
+            let rows = statement.query_map([], |row| row.get(0))?;
+            Ok(rows.collect::<rusqlite::Result<Vec<String>>>()?)
+        })
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn prepare(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn is_writable(&self) -> bool {
+        self.with_connection(|connection| {
+            Ok(connection.execute("DELETE FROM cache_entries WHERE key = ''", [])?)
+        })
+        .is_ok()
+    }
+
+    fn read_app_config(&self) -> Result<Option<String>> {
+        self.read_data("SELECT data FROM app_config WHERE id = ?1", "1")
+    }
+
+    fn write_app_config(&self, data: &str) -> Result<()> {
+        self.write_data(
+            "INSERT OR REPLACE INTO app_config (id, data) VALUES (?1, ?2)",
+            "1",
+            data,
+        )
+    }
+
+    fn list_installation_ids(&self) -> Result<Vec<u128>> {
+        let mut ids: Vec<u128> = self
+            .read_column("SELECT installation_id FROM repository_configs")?
+            .iter()
+            .filter_map(|id| id.parse().ok())
+            .collect();
+        ids.sort();
+        Ok(ids)
+    }
+
+    // Stored as text, SQLite integers are limited to 64 bits
+    fn read_repository_config(&self, installation_id: u128) -> Result<Option<String>> {
+        self.read_data(
+            "SELECT data FROM repository_configs WHERE installation_id = ?1",
+            &installation_id.to_string(),
+        )
+    }
+
+    fn write_repository_config(&self, installation_id: u128, data: &str) -> Result<()> {
+        self.write_data(
+            "INSERT OR REPLACE INTO repository_configs (installation_id, data) VALUES (?1, ?2)",
+            &installation_id.to_string(),
+            data,
+        )
+    }
+
+    fn delete_repository_config(&self, installation_id: u128) -> Result<bool> {
+        self.with_connection(|connection| {
+            let deleted = connection.execute(
+                "DELETE FROM repository_configs WHERE installation_id = ?1",
+                [installation_id.to_string()],
+            )?;
+            Ok(deleted > 0)
+        })
+    }
+
+    fn list_token_keys(&self) -> Result<Vec<String>> {
+        self.read_column("SELECT key FROM tokens ORDER BY key")
+    }
+
+    fn read_token(&self, key: &str) -> Result<Option<String>> {
+        self.read_data("SELECT data FROM tokens WHERE key = ?1", key)
+    }
+
+    fn write_token(&self, key: &str, data: &str) -> Result<()> {
+        self.write_data(
+            "INSERT OR REPLACE INTO tokens (key, data) VALUES (?1, ?2)",
+            key,
+            data,
+        )
+    }
+
+    fn delete_token(&self, key: &str) -> Result<()> {
+        self.with_connection(|connection| {
+            connection.execute("DELETE FROM tokens WHERE key = ?1", [key])?;
+            Ok(())
+        })
+    }
+
+    fn list_deliveries(&self) -> Result<Vec<String>> {
+        self.read_column("SELECT data FROM deliveries ORDER BY delivery_id")
+    }
+
+    fn read_delivery(&self, delivery_id: &str) -> Result<Option<String>> {
+        self.read_data(
+            "SELECT data FROM deliveries WHERE delivery_id = ?1",
+            delivery_id,
+        )
+    }
+
+    fn write_delivery(&self, delivery_id: &str, data: &str) -> Result<()> {
+        self.write_data(
+            "INSERT OR REPLACE INTO deliveries (delivery_id, data) VALUES (?1, ?2)",
+            delivery_id,
+            data,
+        )
+    }
+
+    fn delete_delivery(&self, delivery_id: &str) -> Result<()> {
+        self.with_connection(|connection| {
+            connection.execute(
+                "DELETE FROM deliveries WHERE delivery_id = ?1",
+                [delivery_id],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn append_audit_entry(&self, data: &str) -> Result<()> {
+        self.with_connection(|connection| {
+            connection.execute(
+                "INSERT INTO audit_entries (data) VALUES (?1)",
+                params![data],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn read_audit_entries(&self) -> Result<Vec<String>> {
+        self.read_column("SELECT data FROM audit_entries ORDER BY id")
+    }
+
+    fn read_cache_entry(&self, key: &str) -> Result<Option<String>> {
+        self.read_data("SELECT data FROM cache_entries WHERE key = ?1", key)
+    }
+
+    fn write_cache_entry(&self, key: &str, data: &str) -> Result<()> {
+        self.write_data(
+            "INSERT OR REPLACE INTO cache_entries (key, data) VALUES (?1, ?2)",
+            key,
+            data,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::FileStorage;
+    use tempfile::TempDir;
+
+    #[test]
+    fn migrates_once_and_keeps_data() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("app.db");
+        let storage = SqliteStorage::open(&path).unwrap();
+        storage.write_repository_config(42, "{\"a\":1}").unwrap();
+        storage.append_audit_entry("{\"n\":1}").unwrap();
+        storage.append_audit_entry("{\"n\":2}").unwrap();
+        drop(storage);
+
+        let storage = SqliteStorage::open(&path).unwrap();
+        let version: i64 = storage
+            .with_connection(|connection| {
+                Ok(connection.query_row("PRAGMA user_version", [], |row| row.get(0))?)
+            })
+            .unwrap();
+        assert_eq!(version as usize, MIGRATIONS.len());
+        assert_eq!(storage.list_installation_ids().unwrap(), vec![42]);
+        assert_eq!(
+            storage.read_audit_entries().unwrap(),
+            vec!["{\"n\":1}", "{\"n\":2}"]
+        );
+        assert!(storage.delete_repository_config(42).unwrap());
+        assert!(!storage.delete_repository_config(42).unwrap());
+        assert_eq!(storage.read_repository_config(42).unwrap(), None);
+        assert!(storage.is_writable());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restricts_the_database_files_to_the_owner() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("app.db");
+        let storage = SqliteStorage::open(&path).unwrap();
+        storage.write_token("7-abc", "enc:v1:AAAA").unwrap();
+        for file in ["app.db", "app.db-wal", "app.db-shm"] {
+            let mode = fs::metadata(dir.path().join(file))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600, "{file}");
+        }
+    }
+
+    #[test]
+    fn imports_the_folders() {
+        let dir = TempDir::new().unwrap();
+        let storage = SqliteStorage::open(&dir.path().join("app.db")).unwrap();
+
+        let files = TempDir::new().unwrap();
+        let folders = FileStorage::new(files.path());
+        folders.prepare().unwrap();
+        folders.write_app_config("{\"app_id\":1}").unwrap();
+        folders
+            .write_repository_config(7, "{\"file_to_donwload\":\"Cargo.toml\"}")
+            .unwrap();
+        folders.write_token("7-abc", "enc:v1:AAAA").unwrap();
+        folders.write_delivery("d-1", "{\"id\":\"d-1\"}").unwrap();
+        folders.write_delivery("d-2", "{\"id\":").unwrap();
+        folders
+            .append_audit_entry("{\"outcome\":\"bumped\"}")
+            .unwrap();
+        let summary = storage.import(&folders, false).unwrap();
+
+        assert!(summary.app_config);
+        assert_eq!(
+            (
+                summary.repository_configs,
+                summary.tokens,
+                summary.deliveries,
+                summary.skipped_deliveries,
+                summary.audit_entries
+            ),
+            (1, 1, 1, 1, 1)
+        );
+        assert_eq!(
+            storage.read_app_config().unwrap().as_deref(),
+            Some("{\"app_id\":1}")
+        );
+        assert_eq!(
+            storage.read_token("7-abc").unwrap().as_deref(),
+            Some("enc:v1:AAAA")
+        );
+        assert_eq!(
+            storage.read_delivery("d-1").unwrap().as_deref(),
+            Some("{\"id\":\"d-1\"}")
+        );
+        assert_eq!(storage.read_audit_entries().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn refuses_to_import_twice_without_force() {
+        let dir = TempDir::new().unwrap();
+        let storage = SqliteStorage::open(&dir.path().join("app.db")).unwrap();
+        let files = TempDir::new().unwrap();
+        let folders = FileStorage::new(files.path());
+        folders.prepare().unwrap();
+        folders.append_audit_entry("{\"n\":1}").unwrap();
+
+        storage.import(&folders, false).unwrap();
+        let Err(err) = storage.import(&folders, false) else {
+            panic!("expected the second import to be refused");
+        };
+        assert!(matches!(
+            err.downcast_ref::<AppErrors>(),
+            Some(AppErrors::ImportTargetNotEmpty(_))
+        ));
+        assert_eq!(storage.read_audit_entries().unwrap().len(), 1);
+
+        storage.import(&folders, true).unwrap();
+        assert_eq!(storage.read_audit_entries().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn rolls_back_a_failed_transaction() {
+        let dir = TempDir::new().unwrap();
+        let storage = SqliteStorage::open(&dir.path().join("app.db")).unwrap();
+        let result: Result<()> = storage.in_transaction(|| {
+            storage.write_token("7-abc", "enc:v1:AAAA")?;
+            anyhow::bail!("failed halfway")
+        });
+        assert!(result.is_err());
+        assert_eq!(storage.read_token("7-abc").unwrap(), None);
+        storage.write_token("7-abc", "enc:v1:AAAA").unwrap();
+    }
+}
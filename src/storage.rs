@@ -0,0 +1,568 @@
+use std::{
+    env,
+    fs::{self, DirBuilder, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+};
+
+use anyhow::{bail, Result};
+use tracing::{info, warn};
+
+use crate::{app_errors::AppErrors, sqlite_storage::SqliteStorage};
+
+static CONFIG_DATA_PATH: &str = "config";
+static CONFIG_FILE_APP: &str = "IncreaseAppVersion.json";
+static TOKENS_DATA_PATH: &str = "tokens";
+static DELIVERIES_DATA_PATH: &str = "deliveries";
+static AUDIT_DATA_PATH: &str = "audit";
+static AUDIT_FILE: &str = "audit.jsonl";
+static DATABASE_PATH_VAR: &str = "DATABASE_PATH";
+static STORAGE_BACKEND_VAR: &str = "STORAGE_BACKEND";
+static DEFAULT_DATABASE_FILE: &str = "app.db";
+static DATA_DIR_NAME: &str = "increase_version_app";
+
+// Persistent state of the app. Records are stored as the JSON written by the modules owning them,
+// so a backend only decides where they live
+pub trait Storage: Send + Sync {
+    fn prepare(&self) -> Result<()>;
+    fn is_writable(&self) -> bool;
+
+    fn read_app_config(&self) -> Result<Option<String>>;
+    fn write_app_config(&self, data: &str) -> Result<()>;
+
+    fn list_installation_ids(&self) -> Result<Vec<u128>>;
+    fn read_repository_config(&self, installation_id: u128) -> Result<Option<String>>;
+    fn write_repository_config(&self, installation_id: u128, data: &str) -> Result<()>;
+    // Returns false when there was no config to delete
+    fn delete_repository_config(&self, installation_id: u128) -> Result<bool>;
+
+    fn list_token_keys(&self) -> Result<Vec<String>>;
+    fn read_token(&self, key: &str) -> Result<Option<String>>;
+    fn write_token(&self, key: &str, data: &str) -> Result<()>;
+    fn delete_token(&self, key: &str) -> Result<()>;
+
+    fn list_deliveries(&self) -> Result<Vec<String>>;
+    fn read_delivery(&self, delivery_id: &str) -> Result<Option<String>>;
+    fn write_delivery(&self, delivery_id: &str, data: &str) -> Result<()>;
+    fn delete_delivery(&self, delivery_id: &str) -> Result<()>;
+
+    fn append_audit_entry(&self, data: &str) -> Result<()>;
+    // Oldest first
+    fn read_audit_entries(&self) -> Result<Vec<String>>;
+
+    fn read_cache_entry(&self, key: &str) -> Result<Option<String>>;
+    fn write_cache_entry(&self, key: &str, data: &str) -> Result<()>;
+}
+
+static STORAGE: OnceLock<Box<dyn Storage>> = OnceLock::new();
+static DATA_DIR: OnceLock<PathBuf> = OnceLock::new();
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+fn xdg_data_dir(xdg_data_home: Option<String>, home: Option<String>) -> PathBuf {
+    match (xdg_data_home, home) {
+        (Some(xdg_data_home), _) if !xdg_data_home.is_empty() => {
+            Path::new(&xdg_data_home).join(DATA_DIR_NAME)
+        }
+        (_, Some(home)) if !home.is_empty() => Path::new(&home)
+            .join(".local")
+            .join("share")
+            .join(DATA_DIR_NAME),
+        _ => PathBuf::from("."),
+    }
+}
+
+// `--data-dir` or `DATA_DIR`, then the working directory when it holds the folders of an older
+// install, then `$XDG_DATA_HOME/increase_version_app` or `~/.local/share/increase_version_app`
+pub fn resolve_data_dir(explicit: Option<PathBuf>) -> PathBuf {
+    if let Some(data_dir) = explicit {
+        return data_dir;
+    }
+    if Path::new(CONFIG_DATA_PATH).join(CONFIG_FILE_APP).exists() {
+        warn!("Using the data folders of the working directory, set --data-dir or DATA_DIR to keep them elsewhere");
+        return PathBuf::from(".");
+    }
+    xdg_data_dir(env::var("XDG_DATA_HOME").ok(), env::var("HOME").ok())
+}
+
+// Only the owner can list the data directory, it holds the installation tokens. A directory of an
+// older version or created by hand is restricted too
+pub fn create_data_dir(data_dir: &Path) -> Result<()> {
+    if data_dir.exists() {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(data_dir)?.permissions().mode() & 0o777;
+            if mode != 0o700 {
+                match fs::set_permissions(data_dir, fs::Permissions::from_mode(0o700)) {
+                    Ok(()) => info!(
+                        "Restricted data directory {} from {mode:o} to 700",
+                        data_dir.display()
+                    ),
+                    Err(err) => warn!(
+                        "Data directory {} is {mode:o} and could not be restricted to 700: {err}",
+                        data_dir.display()
+                    ),
+                }
+            }
+        }
+        return Ok(());
+    }
+    let mut builder = DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(data_dir)?;
+    Ok(())
+}
+
+// A relative path is taken from the data directory, `app.db` in it by default
+pub fn database_path(data_dir: &Path, path: Option<&Path>) -> PathBuf {
+    data_dir.join(path.unwrap_or(Path::new(DEFAULT_DATABASE_FILE)))
+}
+
+// SQLite with `STORAGE_BACKEND=sqlite` or when `DATABASE_PATH` is set, `STORAGE_BACKEND=files`
+// keeps the folders of the data directory
+fn sqlite_database(
+    data_dir: &Path,
+    backend: Option<String>,
+    path: Option<String>,
+) -> Result<Option<PathBuf>> {
+    match (backend.as_deref(), path) {
+        (Some("sqlite"), path) | (None, path @ Some(_)) => Ok(Some(database_path(
+            data_dir,
+            path.as_deref().map(Path::new),
+        ))),
+        (Some("files"), _) | (None, None) => Ok(None),
+        (Some(_), _) => bail!(AppErrors::InvalidEvironmentVariable(
+            STORAGE_BACKEND_VAR.to_string(),
+            "expected `files` or `sqlite`"
+        )),
+    }
+}
+
+pub fn init_storage(data_dir: Option<PathBuf>) -> Result<()> {
+    let data_dir = resolve_data_dir(data_dir);
+    create_data_dir(&data_dir)?;
+    info!("Using data directory {}", data_dir.display());
+    let non_empty_var = |var: &str| env::var(var).ok().filter(|value| !value.is_empty());
+    let database = sqlite_database(
+        &data_dir,
+        non_empty_var(STORAGE_BACKEND_VAR),
+        non_empty_var(DATABASE_PATH_VAR),
+    )?;
+    let storage: Box<dyn Storage> = match database {
+        Some(path) => {
+            info!("Using SQLite storage {}", path.display());
+            Box::new(SqliteStorage::open(&path)?)
+        }
+        None => Box::new(FileStorage::new(&data_dir)),
+    };
+    storage.prepare()?;
+    let _ = DATA_DIR.set(data_dir);
+    let _ = STORAGE.set(storage);
+    Ok(())
+}
+
+pub fn data_dir() -> &'static Path {
+    DATA_DIR.get_or_init(|| resolve_data_dir(None))
+}
+
+pub fn storage() -> &'static dyn Storage {
+    STORAGE
+        .get_or_init(|| Box::new(FileStorage::new(data_dir())))
+        .as_ref()
+}
+
+// Tests going through `storage()` share one throwaway data directory instead of the real one
+#[cfg(test)]
+pub fn init_test_storage() {
+    static TEST_DIR: OnceLock<tempfile::TempDir> = OnceLock::new();
+    let dir = TEST_DIR.get_or_init(|| tempfile::TempDir::new().expect("temporary data directory"));
+    DATA_DIR.get_or_init(|| dir.path().to_path_buf());
+    STORAGE.get_or_init(|| {
+        let storage = FileStorage::new(dir.path());
+        storage.prepare().expect("test storage folders");
+        Box::new(storage)
+    });
+}
+
+// Written next to `path` then renamed over it, readers never see a partially written file and a
+// crash leaves the previous content in place
+fn write_atomic(path: &Path, data: &str, mode: u32) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let temp_path = path.with_file_name(format!(
+        ".{file_name}.{}.{}.tmp",
+        process::id(),
+        TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    let written = options.open(&temp_path).and_then(|mut file| {
+        file.write_all(data.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)
+    });
+    if written.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    Ok(written?)
+}
+
+fn read_optional(path: &Path) -> Result<Option<String>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(fs::read_to_string(path)?))
+}
+
+fn create_folder(path: &Path) -> Result<()> {
+    if !path.exists() {
+        fs::create_dir(path)?;
+    }
+    Ok(())
+}
+
+// Files stems of `folder` with the `json` extension
+fn list_json_stems(folder: &Path) -> Result<Vec<String>> {
+    let mut stems: Vec<String> = fs::read_dir(folder)?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if path.extension()? != "json" {
+                return None;
+            }
+            Some(path.file_stem()?.to_str()?.to_string())
+        })
+        .collect();
+    stems.sort();
+    Ok(stems)
+}
+
+// JSON files under `config/`, `tokens/`, `deliveries/` and `audit/` of `root`
+pub struct FileStorage {
+    root: PathBuf,
+}
+
+impl FileStorage {
+    pub fn new(root: &Path) -> FileStorage {
+        FileStorage {
+            root: root.to_path_buf(),
+        }
+    }
+
+    fn folder(&self, folder: &str) -> PathBuf {
+        self.root.join(folder)
+    }
+
+    fn config_path(&self, file: &str) -> PathBuf {
+        self.folder(CONFIG_DATA_PATH).join(file)
+    }
+
+    fn token_path(&self, key: &str) -> PathBuf {
+        self.folder(TOKENS_DATA_PATH).join(format!("{key}.json"))
+    }
+
+    fn delivery_path(&self, delivery_id: &str) -> PathBuf {
+        self.folder(DELIVERIES_DATA_PATH)
+            .join(format!("{delivery_id}.json"))
+    }
+
+    fn audit_path(&self) -> PathBuf {
+        self.folder(AUDIT_DATA_PATH).join(AUDIT_FILE)
+    }
+}
+
+impl Storage for FileStorage {
+    fn prepare(&self) -> Result<()> {
+        for folder in [
+            CONFIG_DATA_PATH,
+            TOKENS_DATA_PATH,
+            DELIVERIES_DATA_PATH,
+            AUDIT_DATA_PATH,
+        ] {
+            create_folder(&self.folder(folder))?;
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(
+                self.folder(TOKENS_DATA_PATH),
+                fs::Permissions::from_mode(0o700),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn is_writable(&self) -> bool {
+        let probe = self.folder(TOKENS_DATA_PATH).join(".write_probe");
+        let writable = fs::write(&probe, b"").is_ok();
+        let _ = fs::remove_file(probe);
+        writable
+    }
+
+    fn read_app_config(&self) -> Result<Option<String>> {
+        read_optional(&self.config_path(CONFIG_FILE_APP))
+    }
+
+    fn write_app_config(&self, data: &str) -> Result<()> {
+        write_atomic(&self.config_path(CONFIG_FILE_APP), data, 0o644)
+    }
+
+    fn list_installation_ids(&self) -> Result<Vec<u128>> {
+        let mut ids: Vec<u128> = list_json_stems(&self.folder(CONFIG_DATA_PATH))?
+            .iter()
+            .filter_map(|stem| stem.parse::<u128>().ok())
+            .collect();
+        ids.sort();
+        Ok(ids)
+    }
+
+    fn read_repository_config(&self, installation_id: u128) -> Result<Option<String>> {
+        read_optional(&self.config_path(&format!("{installation_id}.json")))
+    }
+
+    fn write_repository_config(&self, installation_id: u128, data: &str) -> Result<()> {
+        write_atomic(
+            &self.config_path(&format!("{installation_id}.json")),
+            data,
+            0o644,
+        )
+    }
+
+    fn delete_repository_config(&self, installation_id: u128) -> Result<bool> {
+        let path = self.config_path(&format!("{installation_id}.json"));
+        if !path.exists() {
+            return Ok(false);
+        }
+        fs::remove_file(path)?;
+        Ok(true)
+    }
+
+    fn list_token_keys(&self) -> Result<Vec<String>> {
+        list_json_stems(&self.folder(TOKENS_DATA_PATH))
+    }
+
+    fn read_token(&self, key: &str) -> Result<Option<String>> {
+        read_optional(&self.token_path(key))
+    }
+
+    // Only readable by the owner, the rename also replaces files written by older versions
+    fn write_token(&self, key: &str, data: &str) -> Result<()> {
+        write_atomic(&self.token_path(key), data, 0o600)
+    }
+
+    fn delete_token(&self, key: &str) -> Result<()> {
+        fs::remove_file(self.token_path(key))?;
+        Ok(())
+    }
+
+    fn list_deliveries(&self) -> Result<Vec<String>> {
+        let mut deliveries = Vec::new();
+        for stem in list_json_stems(&self.folder(DELIVERIES_DATA_PATH))? {
+            match fs::read_to_string(self.delivery_path(&stem)) {
+                Ok(data) => deliveries.push(data),
+                Err(err) => warn!("Skipping unreadable delivery {stem}: {err}"),
+            }
+        }
+        Ok(deliveries)
+    }
+
+    fn read_delivery(&self, delivery_id: &str) -> Result<Option<String>> {
+        read_optional(&self.delivery_path(delivery_id))
+    }
+
+    fn write_delivery(&self, delivery_id: &str, data: &str) -> Result<()> {
+        write_atomic(&self.delivery_path(delivery_id), data, 0o644)
+    }
+
+    fn delete_delivery(&self, delivery_id: &str) -> Result<()> {
+        fs::remove_file(self.delivery_path(delivery_id))?;
+        Ok(())
+    }
+
+    fn append_audit_entry(&self, data: &str) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.audit_path())?;
+        file.write_all(format!("{data}\n").as_bytes())?;
+        Ok(())
+    }
+
+    fn read_audit_entries(&self) -> Result<Vec<String>> {
+        let Some(data) = read_optional(&self.audit_path())? else {
+            return Ok(Vec::new());
+        };
+        Ok(data
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(str::to_string)
+            .collect())
+    }
+
+    fn read_cache_entry(&self, key: &str) -> Result<Option<String>> {
+        read_optional(&self.config_path(&format!("{key}.json")))
+    }
+
+    fn write_cache_entry(&self, key: &str, data: &str) -> Result<()> {
+        write_atomic(&self.config_path(&format!("{key}.json")), data, 0o644)
+    }
+}
+
+#[derive(Default)]
+pub struct ImportSummary {
+    pub app_config: bool,
+    pub repository_configs: usize,
+    pub tokens: usize,
+    pub deliveries: usize,
+    pub skipped_deliveries: usize,
+    pub audit_entries: usize,
+}
+
+// Copies the state of `source` into `target`, records already in `target` are overwritten and
+// audit entries are appended. Deliveries without a readable id are skipped with a warning
+pub fn import_storage(source: &dyn Storage, target: &dyn Storage) -> Result<ImportSummary> {
+    let mut summary = ImportSummary::default();
+    if let Some(data) = source.read_app_config()? {
+        target.write_app_config(&data)?;
+        summary.app_config = true;
+    }
+    for installation_id in source.list_installation_ids()? {
+        if let Some(data) = source.read_repository_config(installation_id)? {
+            target.write_repository_config(installation_id, &data)?;
+            summary.repository_configs += 1;
+        }
+    }
+    for key in source.list_token_keys()? {
+        if let Some(data) = source.read_token(&key)? {
+            target.write_token(&key, &data)?;
+            summary.tokens += 1;
+        }
+    }
+    for data in source.list_deliveries()? {
+        let delivery_id = serde_json::from_str::<serde_json::Value>(&data)
+            .ok()
+            .and_then(|delivery| delivery["id"].as_str().map(str::to_string));
+        let Some(delivery_id) = delivery_id else {
+            warn!("Skipping a stored delivery without a readable id");
+            summary.skipped_deliveries += 1;
+            continue;
+        };
+        target.write_delivery(&delivery_id, &data)?;
+        summary.deliveries += 1;
+    }
+    for data in source.read_audit_entries()? {
+        target.append_audit_entry(&data)?;
+        summary.audit_entries += 1;
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn places_the_database_in_the_data_dir() {
+        let data_dir = Path::new("/var/lib/bumper");
+        let database = |backend: Option<&str>, path: Option<&str>| {
+            sqlite_database(
+                data_dir,
+                backend.map(str::to_string),
+                path.map(str::to_string),
+            )
+        };
+        assert_eq!(database(None, None).unwrap(), None);
+        assert_eq!(database(Some("files"), Some("app.db")).unwrap(), None);
+        assert_eq!(
+            database(Some("sqlite"), None).unwrap(),
+            Some(PathBuf::from("/var/lib/bumper/app.db"))
+        );
+        assert_eq!(
+            database(None, Some("state/bumper.db")).unwrap(),
+            Some(PathBuf::from("/var/lib/bumper/state/bumper.db"))
+        );
+        assert_eq!(
+            database(Some("sqlite"), Some("/srv/bumper.db")).unwrap(),
+            Some(PathBuf::from("/srv/bumper.db"))
+        );
+        assert!(database(Some("postgres"), None).is_err());
+    }
+
+    #[test]
+    fn defaults_to_xdg_data_home() {
+        assert_eq!(
+            xdg_data_dir(Some("/data".to_string()), Some("/home/bot".to_string())),
+            Path::new("/data/increase_version_app")
+        );
+        assert_eq!(
+            xdg_data_dir(Some(String::new()), Some("/home/bot".to_string())),
+            Path::new("/home/bot/.local/share/increase_version_app")
+        );
+        assert_eq!(xdg_data_dir(None, None), Path::new("."));
+        assert_eq!(
+            resolve_data_dir(Some(PathBuf::from("/srv/app"))),
+            Path::new("/srv/app")
+        );
+    }
+
+    #[test]
+    fn replaces_files_atomically() {
+        let dir = TempDir::new().unwrap();
+        let storage = FileStorage::new(&dir.path().join("data"));
+        create_data_dir(&storage.root).unwrap();
+        storage.prepare().unwrap();
+        storage.write_repository_config(7, "{\"a\":1}").unwrap();
+        storage.write_repository_config(7, "{\"a\":2}").unwrap();
+        storage.write_token("7-abc", "enc:v1:AAAA").unwrap();
+
+        assert_eq!(
+            storage.read_repository_config(7).unwrap().as_deref(),
+            Some("{\"a\":2}")
+        );
+        assert_eq!(storage.list_installation_ids().unwrap(), vec![7]);
+        let leftovers = fs::read_dir(storage.folder(CONFIG_DATA_PATH))
+            .unwrap()
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .ends_with(".tmp")
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&storage.root), 0o700);
+            assert_eq!(mode(&storage.folder(TOKENS_DATA_PATH)), 0o700);
+            assert_eq!(mode(&storage.token_path("7-abc")), 0o600);
+
+            fs::set_permissions(&storage.root, fs::Permissions::from_mode(0o755)).unwrap();
+            create_data_dir(&storage.root).unwrap();
+            assert_eq!(mode(&storage.root), 0o700);
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use std::{
+    env,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::Result;
+use axum::http::HeaderMap;
+use chrono::Utc;
+use tracing::{field, info_span, Span};
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+#[cfg(feature = "otel")]
+pub use otel::inject_trace_context;
+
+static LOG_FORMAT_VAR: &str = "LOG_FORMAT";
+static LOCAL_DELIVERY_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Level is controlled through `RUST_LOG` (default `info`), `LOG_FORMAT=json` switches to JSON lines
+pub fn init_tracing() -> Result<()> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let json_output = env::var(LOG_FORMAT_VAR).is_ok_and(|format| format == "json");
+
+    let fmt_layer = match json_output {
+        true => fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+        false => fmt::layer().boxed(),
+    };
+
+    let registry = tracing_subscriber::registry().with(filter).with(fmt_layer);
+    #[cfg(feature = "otel")]
+    let registry = registry.with(otel::otlp_layer()?);
+    registry.try_init()?;
+    Ok(())
+}
+
+// Span following a GitHub delivery through the validator, the worker and the API calls
+pub fn delivery_span(headers: &HeaderMap) -> Span {
+    let delivery_id = headers
+        .get("X-GitHub-Delivery")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("unknown");
+    info_span!(
+        "delivery",
+        delivery_id,
+        repository = field::Empty,
+        installation_id = field::Empty
+    )
+}
+
+// Only known once the payload passed validation
+pub fn record_delivery_target(repository: &str, installation_id: u128) {
+    let span = Span::current();
+    span.record("repository", repository);
+    span.record("installation_id", installation_id);
+}
+
+// Plain git servers send no delivery header, local pushes get their own id to correlate the logs
+pub fn local_delivery_id() -> String {
+    let counter = LOCAL_DELIVERY_COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("local-{:x}-{counter:x}", Utc::now().timestamp_millis())
+}
+
+pub fn local_delivery_span(delivery_id: &str) -> Span {
+    info_span!("local_delivery", delivery_id, repository = field::Empty)
+}
+
+// OTLP export of the spans, enabled with the `otel` feature when `OTEL_EXPORTER_OTLP_ENDPOINT` is set
+#[cfg(feature = "otel")]
+mod otel {
+    use std::env;
+
+    use anyhow::Result;
+    use axum::http::{HeaderMap, HeaderName, HeaderValue};
+    use opentelemetry::{global, propagation::Injector, trace::TracerProvider as _};
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::{
+        propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource,
+    };
+    use tracing::{Span, Subscriber};
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::{registry::LookupSpan, Layer};
+
+    static OTLP_ENDPOINT_VAR: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+
+    pub fn otlp_layer<S>() -> Result<Option<impl Layer<S>>>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let Ok(endpoint) = env::var(OTLP_ENDPOINT_VAR) else {
+            return Ok(None);
+        };
+
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+            .build()?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(env!("CARGO_PKG_NAME"))
+                    .build(),
+            )
+            .build();
+        let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        global::set_tracer_provider(provider);
+
+        Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+    }
+
+    struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+    impl Injector for HeaderInjector<'_> {
+        fn set(&mut self, key: &str, value: String) {
+            let Ok(name) = HeaderName::from_bytes(key.as_bytes()) else {
+                return;
+            };
+            let Ok(value) = HeaderValue::from_str(&value) else {
+                return;
+            };
+            self.0.insert(name, value);
+        }
+    }
+
+    // Adds the `traceparent` of the current span so GitHub API calls join the delivery trace
+    pub fn inject_trace_context(headers: &mut HeaderMap) {
+        let context = Span::current().context();
+        global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(&context, &mut HeaderInjector(headers))
+        });
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use opentelemetry_sdk::trace::InMemorySpanExporter;
+        use tracing::info_span;
+        use tracing_subscriber::layer::SubscriberExt;
+
+        #[test]
+        fn exports_delivery_span_and_injects_context() {
+            let exporter = InMemorySpanExporter::default();
+            let provider = SdkTracerProvider::builder()
+                .with_simple_exporter(exporter.clone())
+                .build();
+            global::set_text_map_propagator(TraceContextPropagator::new());
+            let subscriber = tracing_subscriber::registry()
+                .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+            tracing::subscriber::with_default(subscriber, || {
+                let span = info_span!("delivery", delivery_id = "72d3162e");
+                let _entered = span.enter();
+                let mut headers = HeaderMap::new();
+                inject_trace_context(&mut headers);
+                assert!(headers.contains_key("traceparent"));
+            });
+
+            provider.force_flush().unwrap();
+            let spans = exporter.get_finished_spans().unwrap();
+            assert!(spans.iter().any(|span| span.name == "delivery"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        fmt::Debug,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::http::HeaderValue;
+    use tracing::{
+        field::{Field, Visit},
+        span::{Attributes, Id, Record},
+        Subscriber,
+    };
+    use tracing_subscriber::{layer::Context, prelude::*};
+
+    use super::*;
+
+    // Collects every field recorded on any span
+    #[derive(Clone, Default)]
+    struct RecordedFields(Arc<Mutex<BTreeMap<String, String>>>);
+
+    impl Visit for RecordedFields {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(field.name().to_string(), format!("{value:?}"));
+        }
+    }
+
+    impl<S: Subscriber> tracing_subscriber::Layer<S> for RecordedFields {
+        fn on_new_span(&self, attrs: &Attributes<'_>, _: &Id, _: Context<'_, S>) {
+            attrs.record(&mut self.clone());
+        }
+
+        fn on_record(&self, _: &Id, values: &Record<'_>, _: Context<'_, S>) {
+            values.record(&mut self.clone());
+        }
+    }
+
+    #[test]
+    fn records_the_delivery_target_on_the_span() {
+        let recorded = RecordedFields::default();
+        let subscriber = tracing_subscriber::registry().with(recorded.clone());
+        let mut headers = HeaderMap::new();
+        headers.insert("X-GitHub-Delivery", HeaderValue::from_static("72d3162e"));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = delivery_span(&headers);
+            let _entered = span.enter();
+            record_delivery_target("octo/app", 42);
+        });
+
+        let fields = recorded.0.lock().unwrap();
+        assert_eq!(
+            fields.get("delivery_id").map(String::as_str),
+            Some("\"72d3162e\"")
+        );
+        assert_eq!(
+            fields.get("repository").map(String::as_str),
+            Some("\"octo/app\"")
+        );
+        assert_eq!(
+            fields.get("installation_id").map(String::as_str),
+            Some("42")
+        );
+    }
+
+    #[test]
+    fn local_delivery_ids_are_unique() {
+        assert_ne!(local_delivery_id(), local_delivery_id());
+    }
+}
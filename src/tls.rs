@@ -0,0 +1,208 @@
+use std::{fs, sync::Arc, time::Duration, time::SystemTime};
+
+use anyhow::{ensure, Context, Result};
+use arc_swap::ArcSwap;
+use rustls::{server::WebPkiClientVerifier, RootCertStore, ServerConfig};
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use tracing::{info, warn};
+
+use crate::app_config::TlsConfig;
+
+pub type SharedServerConfig = Arc<ArcSwap<ServerConfig>>;
+
+static CERT_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+// A client must not hold a connection open without completing the handshake
+pub static TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn read_certificates(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .with_context(|| format!("could not read certificates from {path}"))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid certificate in {path}"))?;
+    ensure!(!certificates.is_empty(), "no certificate found in {path}");
+    Ok(certificates)
+}
+
+// Client certificates are optional at the TLS level so GitHub can still deliver without one,
+// the admin routes check that a verified certificate was presented
+pub fn load_server_config(tls: &TlsConfig) -> Result<ServerConfig> {
+    let certificates = read_certificates(&tls.cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(&tls.key_path)
+        .with_context(|| format!("could not read private key from {}", tls.key_path))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = match &tls.client_ca_path {
+        Some(client_ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in read_certificates(client_ca_path)? {
+                roots.add(certificate)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .allow_unauthenticated()
+                .build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder.with_single_cert(certificates, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+fn modified_times(tls: &TlsConfig) -> Vec<Option<SystemTime>> {
+    [
+        Some(&tls.cert_path),
+        Some(&tls.key_path),
+        tls.client_ca_path.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+    .collect()
+}
+
+// Picks up renewed certificates (certbot replaces the files in place), a broken renewal keeps
+// the current certificate until the files are fixed
+pub fn spawn_certificate_reloader(shared: SharedServerConfig, tls: TlsConfig) {
+    spawn_reloader_every(shared, tls, CERT_CHECK_INTERVAL);
+}
+
+fn spawn_reloader_every(shared: SharedServerConfig, tls: TlsConfig, period: Duration) {
+    tokio::spawn(async move {
+        let mut last_modified = modified_times(&tls);
+        let mut interval = tokio::time::interval(period);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let modified = modified_times(&tls);
+            if modified == last_modified {
+                continue;
+            }
+            match load_server_config(&tls) {
+                Ok(config) => {
+                    shared.store(Arc::new(config));
+                    last_modified = modified;
+                    info!("Reloaded TLS certificate from {}", tls.cert_path);
+                }
+                Err(err) => {
+                    warn!("Failed to reload TLS certificate, keeping the current one: {err:#}")
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{app_config::AppConfig, app_config::IpFilter, server::serve_connections};
+    use axum::Router;
+    use rcgen::{generate_simple_self_signed, CertifiedKey};
+    use rustls::ClientConfig;
+    use rustls_pki_types::ServerName;
+    use tempfile::TempDir;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::TlsConnector;
+
+    // Returns the paths and the DER of the certificate
+    fn write_certificate(dir: &TempDir, name: &str) -> (String, String, CertificateDer<'static>) {
+        let CertifiedKey { cert, signing_key } =
+            generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_path = dir.path().join(format!("{name}.pem"));
+        let key_path = dir.path().join(format!("{name}.key"));
+        fs::write(&cert_path, cert.pem()).unwrap();
+        fs::write(&key_path, signing_key.serialize_pem()).unwrap();
+        (
+            cert_path.to_string_lossy().to_string(),
+            key_path.to_string_lossy().to_string(),
+            cert.der().clone(),
+        )
+    }
+
+    // Certificate presented by the server to a client trusting `trusted`
+    async fn served_certificate(
+        address: std::net::SocketAddr,
+        trusted: &[CertificateDer<'static>],
+    ) -> CertificateDer<'static> {
+        let mut roots = RootCertStore::empty();
+        for certificate in trusted {
+            roots.add(certificate.clone()).unwrap();
+        }
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = TcpStream::connect(address).await.unwrap();
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        stream.get_ref().1.peer_certificates().unwrap()[0].clone()
+    }
+
+    #[test]
+    fn loads_certificates_and_client_ca() {
+        let dir = TempDir::new().unwrap();
+        let (cert_path, key_path, _) = write_certificate(&dir, "server");
+        let (client_ca_path, _, _) = write_certificate(&dir, "client_ca");
+        let mut tls = TlsConfig {
+            cert_path,
+            key_path,
+            client_ca_path: None,
+        };
+        assert!(load_server_config(&tls).is_ok());
+
+        tls.client_ca_path = Some(client_ca_path);
+        assert!(load_server_config(&tls).is_ok());
+
+        tls.key_path = tls.cert_path.clone();
+        assert!(load_server_config(&tls).is_err());
+    }
+
+    #[tokio::test]
+    async fn serves_the_rewritten_certificate() {
+        let dir = TempDir::new().unwrap();
+        let (cert_path, key_path, old_certificate) = write_certificate(&dir, "server");
+        let tls = TlsConfig {
+            cert_path,
+            key_path,
+            client_ca_path: None,
+        };
+        let shared: SharedServerConfig =
+            Arc::new(ArcSwap::from_pointee(load_server_config(&tls).unwrap()));
+        spawn_reloader_every(shared.clone(), tls, Duration::from_millis(20));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let ip_filter = Arc::new(IpFilter::new(&AppConfig::default()).unwrap());
+        tokio::spawn(serve_connections(
+            listener,
+            Router::new(),
+            ip_filter,
+            false,
+            Some(shared),
+        ));
+        assert_eq!(
+            served_certificate(address, std::slice::from_ref(&old_certificate)).await,
+            old_certificate
+        );
+
+        // Renewed in place, like certbot does
+        let (_, _, new_certificate) = write_certificate(&dir, "server");
+        let trusted = [old_certificate, new_certificate.clone()];
+        let mut served = served_certificate(address, &trusted).await;
+        for _ in 0..100 {
+            if served == new_certificate {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            served = served_certificate(address, &trusted).await;
+        }
+        assert_eq!(served, new_certificate);
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use anyhow::Result;
+use chrono::TimeDelta;
+use tokio::sync::Mutex as AsyncMutex;
+use tracing::info;
+
+use crate::{installation_token_data::InstallationToken, metrics::record_token_lookup};
+
+type CachedToken = Arc<AsyncMutex<Option<InstallationToken>>>;
+
+// Installation tokens kept in memory between deliveries, keyed by `TokenScope::cache_key`. Each
+// key has its own lock, so concurrent deliveries wait for a single refresh instead of all
+// requesting a new token
+#[derive(Default)]
+pub struct TokenCache {
+    entries: Mutex<HashMap<String, CachedToken>>,
+}
+
+pub static TOKEN_CACHE: LazyLock<TokenCache> = LazyLock::new(TokenCache::default);
+
+impl TokenCache {
+    fn entry(&self, key: &str) -> CachedToken {
+        self.entries
+            .lock()
+            .expect("token cache lock poisoned")
+            .entry(key.to_string())
+            .or_default()
+            .clone()
+    }
+
+    // Tokens expiring within `margin` are refreshed, the disk copy is only read after a restart
+    pub async fn get_or_refresh<L, R, F>(
+        &self,
+        key: &str,
+        margin: TimeDelta,
+        load_from_disk: L,
+        refresh: R,
+    ) -> Result<String>
+    where
+        L: FnOnce() -> Option<InstallationToken>,
+        R: FnOnce() -> F,
+        F: Future<Output = Result<InstallationToken>>,
+    {
+        let entry = self.entry(key);
+        let mut cached = entry.lock().await;
+        if cached.is_none() {
+            *cached = load_from_disk();
+        }
+        if let Some(token) = cached.as_ref() {
+            if token.is_token_valid_for(margin) {
+                record_token_lookup(true);
+                return Ok(token.token.clone());
+            }
+        }
+
+        record_token_lookup(false);
+        info!("Refreshing installation token {key}");
+        let token = refresh().await?;
+        let value = token.token.clone();
+        *cached = Some(token);
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use std::{
+        collections::BTreeMap,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    fn token(value: &str, expires_in: TimeDelta) -> InstallationToken {
+        InstallationToken {
+            token: value.to_string(),
+            expires_at: (Utc::now() + expires_in).to_rfc3339(),
+            permissions: BTreeMap::from([("contents".to_string(), "write".to_string())]),
+            repository_selection: "all".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn deduplicates_concurrent_refreshes() {
+        let cache = Arc::new(TokenCache::default());
+        let refreshes = Arc::new(AtomicUsize::new(0));
+        let mut lookups = Vec::new();
+        for _ in 0..8 {
+            let cache = cache.clone();
+            let refreshes = refreshes.clone();
+            lookups.push(tokio::spawn(async move {
+                cache
+                    .get_or_refresh(
+                        "1-firmware",
+                        TimeDelta::minutes(5),
+                        || None,
+                        || async {
+                            refreshes.fetch_add(1, Ordering::SeqCst);
+                            tokio::time::sleep(Duration::from_millis(20)).await;
+                            Ok(token("fresh", TimeDelta::hours(1)))
+                        },
+                    )
+                    .await
+                    .unwrap()
+            }));
+        }
+        for lookup in lookups {
+            assert_eq!(lookup.await.unwrap(), "fresh");
+        }
+        assert_eq!(refreshes.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn refreshes_before_expiry_and_reads_disk_once() {
+        let cache = TokenCache::default();
+        let margin = TimeDelta::minutes(5);
+        let value = cache
+            .get_or_refresh(
+                "2-firmware",
+                margin,
+                || Some(token("disk", TimeDelta::hours(1))),
+                || async { Ok(token("fresh", TimeDelta::hours(1))) },
+            )
+            .await
+            .unwrap();
+        assert_eq!(value, "disk");
+
+        let value = cache
+            .get_or_refresh(
+                "3-firmware",
+                margin,
+                || Some(token("expiring", TimeDelta::minutes(2))),
+                || async { Ok(token("fresh", TimeDelta::hours(1))) },
+            )
+            .await
+            .unwrap();
+        assert_eq!(value, "fresh");
+
+        let value = cache
+            .get_or_refresh(
+                "3-firmware",
+                margin,
+                || panic!("the disk copy is only read when nothing is cached"),
+                || async { Ok(token("other", TimeDelta::hours(1))) },
+            )
+            .await
+            .unwrap();
+        assert_eq!(value, "fresh");
+    }
+}
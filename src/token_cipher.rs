@@ -0,0 +1,119 @@
+use anyhow::{bail, ensure, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    hkdf::{Salt, HKDF_SHA256},
+    rand::{SecureRandom, SystemRandom},
+};
+
+use crate::{app_config::AppConfig, app_errors::AppErrors};
+
+static ENCRYPTED_PREFIX: &str = "enc:v1:";
+static KEY_SALT: &[u8] = b"increase_version_app";
+static KEY_INFO: &[u8] = b"installation token files v1";
+
+// ChaCha20-Poly1305 with a key derived from `token_encryption_key`, the associated data binds a
+// ciphertext to its file so it cannot be swapped with the one of another installation
+pub struct TokenCipher {
+    key: LessSafeKey,
+    rng: SystemRandom,
+}
+
+impl TokenCipher {
+    pub fn new(master_secret: &str) -> TokenCipher {
+        let prk = Salt::new(HKDF_SHA256, KEY_SALT).extract(master_secret.as_bytes());
+        let info = [KEY_INFO];
+        let okm = prk
+            .expand(&info, &CHACHA20_POLY1305)
+            .expect("key length matches the algorithm");
+        TokenCipher {
+            key: LessSafeKey::new(UnboundKey::from(okm)),
+            rng: SystemRandom::new(),
+        }
+    }
+
+    pub fn from_config(app_config: &AppConfig) -> Option<TokenCipher> {
+        app_config
+            .token_encryption_key
+            .as_deref()
+            .map(TokenCipher::new)
+    }
+
+    pub fn is_encrypted(data: &str) -> bool {
+        data.starts_with(ENCRYPTED_PREFIX)
+    }
+
+    // `enc:v1:` followed by base64 of the nonce and the sealed data
+    pub fn encrypt(&self, associated_data: &str, plaintext: &[u8]) -> Result<String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        if self.rng.fill(&mut nonce).is_err() {
+            bail!(AppErrors::TokenEncryptionFailed(
+                "no random nonce".to_string()
+            ));
+        }
+        let mut sealed = plaintext.to_vec();
+        if self
+            .key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(associated_data.as_bytes()),
+                &mut sealed,
+            )
+            .is_err()
+        {
+            bail!(AppErrors::TokenEncryptionFailed(
+                associated_data.to_string()
+            ));
+        }
+        let mut data = nonce.to_vec();
+        data.append(&mut sealed);
+        Ok(format!("{ENCRYPTED_PREFIX}{}", STANDARD.encode(data)))
+    }
+
+    pub fn decrypt(&self, associated_data: &str, data: &str) -> Result<Vec<u8>> {
+        let failed = || AppErrors::TokenEncryptionFailed(associated_data.to_string());
+        let Some(encoded) = data.trim().strip_prefix(ENCRYPTED_PREFIX) else {
+            bail!(failed());
+        };
+        let Ok(mut data) = STANDARD.decode(encoded) else {
+            bail!(failed());
+        };
+        ensure!(data.len() > NONCE_LEN, failed());
+        let mut sealed = data.split_off(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&data).map_err(|_| failed())?;
+        let Ok(plaintext) =
+            self.key
+                .open_in_place(nonce, Aad::from(associated_data.as_bytes()), &mut sealed)
+        else {
+            bail!(failed());
+        };
+        Ok(plaintext.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypts_for_one_file_and_key() {
+        let cipher = TokenCipher::new("master secret");
+        let data = cipher.encrypt("42.json", b"{\"token\":\"ghs_x\"}").unwrap();
+        assert!(TokenCipher::is_encrypted(&data));
+        assert!(!data.contains("ghs_x"));
+        assert_ne!(
+            data,
+            cipher.encrypt("42.json", b"{\"token\":\"ghs_x\"}").unwrap()
+        );
+
+        assert_eq!(
+            cipher.decrypt("42.json", &data).unwrap(),
+            b"{\"token\":\"ghs_x\"}"
+        );
+        assert!(cipher.decrypt("43.json", &data).is_err());
+        assert!(TokenCipher::new("other secret")
+            .decrypt("42.json", &data)
+            .is_err());
+        assert!(cipher.decrypt("42.json", "{\"token\":\"ghs_x\"}").is_err());
+    }
+}
@@ -0,0 +1,130 @@
+use anyhow::{bail, ensure, Result};
+use clap::ValueEnum;
+use similar::TextDiff;
+
+use crate::app_errors::AppErrors;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum VersionLevel {
+    Major,
+    Minor,
+    Patch,
+}
+
+pub struct VersionBump {
+    pub content: String,
+    pub old_version: String,
+    pub new_version: String,
+}
+
+pub struct FoundVersion<'a> {
+    pub line: &'a str,
+    pub version: &'a str,
+}
+
+// Finds `pattern_version_to_search` in `content` and the `MAJOR.MINOR.PATCH` version that follows it
+pub fn find_version<'a>(
+    content: &'a str,
+    pattern_version_to_search: &str,
+) -> Result<FoundVersion<'a>> {
+    let Some(version_pos) = content.find(pattern_version_to_search) else {
+        let err = format!("Could not find pattern: {}", pattern_version_to_search);
+        bail!(AppErrors::FailedToIncreaseVersionInFile(err));
+    };
+
+    let endline_pos = match content[version_pos..].find('\n') {
+        Some(pos) => pos + version_pos,
+        None => content.len(),
+    };
+
+    let line = content[version_pos..endline_pos].trim_end_matches('\r');
+    let version = line[pattern_version_to_search.len()..].trim_matches(|c| c == ' ' || c == '"');
+
+    let version_split: Vec<&str> = version.split('.').collect();
+    ensure!(
+        version_split.len() == 3 && version_split.iter().all(|p| p.parse::<u32>().is_ok()),
+        AppErrors::FailedToIncreaseVersionInFile(
+            "Failed to obtain version in format: MAJOR.MINOR.PATCH".to_string()
+        )
+    );
+
+    Ok(FoundVersion { line, version })
+}
+
+// Increases only the component selected by `level`, the other ones are kept as they are
+pub fn bump_version_in_content(
+    content: &str,
+    pattern_version_to_search: &str,
+    level: VersionLevel,
+) -> Result<VersionBump> {
+    let found = find_version(content, pattern_version_to_search)?;
+
+    let mut version_split: Vec<String> = found.version.split('.').map(str::to_string).collect();
+    let index = match level {
+        VersionLevel::Major => 0,
+        VersionLevel::Minor => 1,
+        VersionLevel::Patch => 2,
+    };
+    version_split[index] = (version_split[index].parse::<u32>()? + 1).to_string();
+
+    let new_version = version_split.join(".");
+    let final_version = format!("{} \"{}\"", pattern_version_to_search, new_version);
+    let new_content = content.replace(found.line, &final_version);
+
+    Ok(VersionBump {
+        content: new_content,
+        old_version: found.version.to_string(),
+        new_version,
+    })
+}
+
+pub fn commit_message(new_version: &str) -> String {
+    format!("Increase version to {}", new_version)
+}
+
+pub fn unified_diff(path: &str, old_content: &str, new_content: &str) -> String {
+    TextDiff::from_lines(old_content, new_content)
+        .unified_diff()
+        .header(&format!("a/{path}"), &format!("b/{path}"))
+        .to_string()
+}
+
+// Bump applied by the webhook flow
+pub fn increase_version_in_content(
+    content: &str,
+    pattern_version_to_search: &str,
+) -> Result<VersionBump> {
+    bump_version_in_content(content, pattern_version_to_search, VersionLevel::Minor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static PATTERN: &str = "#define VERSION";
+
+    #[test]
+    fn bumps_selected_level_only() {
+        let content = "#pragma once\n#define VERSION \"1.4.2\"\n";
+        let minor = bump_version_in_content(content, PATTERN, VersionLevel::Minor).unwrap();
+        assert_eq!(minor.content, "#pragma once\n#define VERSION \"1.5.2\"\n");
+        let major = bump_version_in_content(content, PATTERN, VersionLevel::Major).unwrap();
+        assert_eq!(major.new_version, "2.4.2");
+        let patch = bump_version_in_content(content, PATTERN, VersionLevel::Patch).unwrap();
+        assert_eq!(patch.old_version, "1.4.2");
+        assert_eq!(patch.new_version, "1.4.3");
+    }
+
+    #[test]
+    fn rejects_missing_pattern_and_bad_versions() {
+        assert!(find_version("#define OTHER \"1.0.0\"", PATTERN).is_err());
+        assert!(find_version("#define VERSION \"1.0\"", PATTERN).is_err());
+        assert!(find_version("#define VERSION \"1.x.0\"", PATTERN).is_err());
+        assert_eq!(
+            find_version("#define VERSION \"3.2.1\"\r\n", PATTERN)
+                .unwrap()
+                .version,
+            "3.2.1"
+        );
+    }
+}
@@ -1,14 +1,19 @@
+// Fields mirror the GitHub push payload, the ones the app does not read are marked as dead code
+
 use serde::Deserialize;
 
 #[derive(Deserialize)]
 pub struct WebHookRepositoryOwner {
+    #[allow(dead_code)]
     pub id: u128,
     pub name: String,
+    #[allow(dead_code)]
     pub email: String,
 }
 
 #[derive(Deserialize)]
 pub struct WebHookRepository {
+    #[allow(dead_code)]
     pub id: u128,
     pub name: String,
     pub full_name: String,
@@ -18,12 +23,14 @@ pub struct WebHookRepository {
 #[derive(Deserialize)]
 pub struct WebHookPusher {
     pub name: String,
+    #[allow(dead_code)]
     pub email: String,
 }
 
 #[derive(Deserialize)]
 pub struct WebHookSender {
     pub login: String,
+    #[allow(dead_code)]
     pub id: u128,
     #[serde(rename = "type")]
     pub type_: String,
@@ -32,25 +39,36 @@ pub struct WebHookSender {
 #[derive(Deserialize)]
 pub struct WebHookInstallation {
     pub id: u128,
+    #[allow(dead_code)]
     pub node_id: String,
 }
 
 #[derive(Deserialize)]
 pub struct WebHookCommitUser {
+    #[allow(dead_code)]
     pub name: String,
+    #[allow(dead_code)]
     pub email: String,
+    #[allow(dead_code)]
     pub username: String,
 }
 
 #[derive(Deserialize)]
 pub struct WebHookCommit {
     pub id: String,
+    #[allow(dead_code)]
     pub tree_id: String,
+    #[allow(dead_code)]
     pub message: String,
+    #[allow(dead_code)]
     pub author: WebHookCommitUser,
+    #[allow(dead_code)]
     pub committer: WebHookCommitUser,
+    #[allow(dead_code)]
     pub added: Vec<String>,
+    #[allow(dead_code)]
     pub removed: Vec<String>,
+    #[allow(dead_code)]
     pub modified: Vec<String>,
 }
 
@@ -64,3 +82,12 @@ pub struct WebWebHook {
     pub installation: WebHookInstallation,
     pub commits: Vec<WebHookCommit>,
 }
+
+// Payload sent by a `post-receive` hook of a plain git server
+#[derive(Deserialize)]
+pub struct LocalPushHook {
+    pub repository: String,
+    #[serde(rename = "ref")]
+    pub ref_: String,
+    pub after: String,
+}
@@ -5,15 +5,62 @@ use crate::{
     app_config::{AppConfig, RepositoryConfig},
     app_errors::AppErrors,
     installation_token_data::{
-        read_installation_data, save_installation_data, InstallationTokenFileContent,
+        read_installation_data, save_installation_data, InstallationTokenFileContent, TokenScope,
     },
+    token_cache::TOKEN_CACHE,
+    token_cipher::TokenCipher,
+    version_bump::{commit_message, unified_diff},
     webhook_data::WebWebHook,
 };
 use anyhow::{bail, ensure, Result};
 use chrono::{TimeDelta, Utc};
 use jsonwebtoken::{self, Algorithm, EncodingKey, Header};
-use log::info;
 use serde::{Deserialize, Serialize};
+use tracing::{info, instrument, warn};
+
+pub struct BumpReport {
+    pub old_version: String,
+    pub new_version: String,
+    pub commit_message: String,
+    pub diff: String,
+    pub dry_run: bool,
+    pub commit_sha: Option<String>,
+}
+
+impl BumpReport {
+    pub fn describe(&self) -> String {
+        match (&self.commit_sha, self.dry_run) {
+            (_, true) => format!("DRY RUN\n{}\n{}", self.commit_message, self.diff),
+            (Some(sha), false) => format!("{} -> {} in {sha}", self.old_version, self.new_version),
+            (None, false) => format!("{} -> {}", self.old_version, self.new_version),
+        }
+    }
+}
+
+pub enum DeliveryOutcome {
+    IgnoredRef,
+    BotSkip,
+    Bumped(BumpReport),
+}
+
+impl DeliveryOutcome {
+    pub fn metric_label(&self) -> &'static str {
+        match self {
+            DeliveryOutcome::IgnoredRef => "ignored_ref",
+            DeliveryOutcome::BotSkip => "bot_skip",
+            DeliveryOutcome::Bumped(report) if report.dry_run => "dry_run",
+            DeliveryOutcome::Bumped(_) => "bumped",
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            DeliveryOutcome::IgnoredRef => "Ignored, ref is not observed".to_string(),
+            DeliveryOutcome::BotSkip => "Ignored, sender is a bot".to_string(),
+            DeliveryOutcome::Bumped(report) => report.describe(),
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize)]
 struct Payload {
@@ -61,36 +108,45 @@ async fn create_jwt(env_vars: &AppConfig) -> Result<String> {
     Ok(encoded_jwt.unwrap())
 }
 
+// Cached token of the scope, requested from GitHub and saved to disk when it expires soon
+pub async fn installation_token(env_vars: &AppConfig, scope: &TokenScope) -> Result<String> {
+    let key = scope.cache_key();
+    let cipher = TokenCipher::from_config(env_vars);
+    let margin = TimeDelta::seconds(env_vars.token_refresh_margin_seconds as i64);
+    TOKEN_CACHE
+        .get_or_refresh(
+            &key,
+            margin,
+            || {
+                read_installation_data(&key, cipher.as_ref())
+                    .map(|installation| installation.token_data)
+            },
+            || async {
+                let jwt = create_jwt(env_vars).await?;
+                let installation = InstallationTokenFileContent {
+                    token_data: get_access_token(scope, jwt.as_str()).await?,
+                };
+                match save_installation_data(&key, &installation, cipher.as_ref()) {
+                    Ok(()) => info!("Saved installation data {key}!"),
+                    Err(err) => warn!("Failed to save installation data {key}: {err}"),
+                }
+                Ok(installation.token_data)
+            },
+        )
+        .await
+}
+
+#[instrument(skip_all)]
 pub async fn increase_version(
     env_vars: &AppConfig,
     repo_config: &RepositoryConfig,
     webhook: WebWebHook,
-) -> Result<()> {
-    let file_name = format!("{}.json", webhook.installation.id);
-    let mut current_installation: Option<InstallationTokenFileContent> =
-        read_installation_data(&file_name);
-    let mut token_needs_saving = false;
-    if current_installation.is_none() {
-        let jwt = create_jwt(env_vars).await?;
-        // let app_data = get_app_info(jwt.as_str()).await?;
-        // info!(
-        //     "Found app id:`{}`, slug:`{}`,name:{}",
-        //     app_data.id, app_data.slug, app_data.name
-        // );
-        current_installation = Some(InstallationTokenFileContent {
-            token_data: get_access_token(webhook.installation.id, jwt.as_str()).await?,
-        });
-        token_needs_saving = true;
-    }
-
-    let installation = current_installation.unwrap();
-    if token_needs_saving {
-        info!("Saved installation data {file_name}!");
-        save_installation_data(&file_name, &installation)?;
-    }
+) -> Result<BumpReport> {
+    let scope = TokenScope::for_repository(webhook.installation.id, &webhook.repository.name);
+    let token = installation_token(env_vars, &scope).await?;
 
     let file_data = get_repo_file_content(
-        &installation.token_data.token,
+        &token,
         &webhook.repository.owner.name,
         &webhook.repository.name,
         &repo_config.file_to_donwload,
@@ -98,6 +154,22 @@ pub async fn increase_version(
     )
     .await?;
 
+    let mut report = BumpReport {
+        old_version: file_data.old_version.clone(),
+        new_version: file_data.new_version.clone(),
+        commit_message: commit_message(&file_data.new_version),
+        diff: unified_diff(&file_data.path, &file_data.old_content, &file_data.content),
+        dry_run: env_vars.dry_run || repo_config.dry_run,
+        commit_sha: None,
+    };
+    if report.dry_run {
+        info!(
+            "Dry run, would bump {} -> {} and commit \"{}\" with:\n{}",
+            report.old_version, report.new_version, report.commit_message, report.diff
+        );
+        return Ok(report);
+    }
+
     ensure!(
         !webhook.commits.is_empty(),
         AppErrors::ApiFailure("increase_version_tree", "No commits available".to_string())
@@ -105,7 +177,7 @@ pub async fn increase_version(
 
     let commit: &String = &webhook.commits[0].id;
     let tree_data = create_tree(
-        &installation.token_data.token,
+        &token,
         &webhook.repository.owner.name,
         &webhook.repository.name,
         commit,
@@ -114,7 +186,7 @@ pub async fn increase_version(
     .await?;
 
     let commit_data = create_commit(
-        &installation.token_data.token,
+        &token,
         &webhook.repository.owner.name,
         &webhook.repository.name,
         commit,
@@ -124,7 +196,7 @@ pub async fn increase_version(
     .await?;
 
     update_a_refence(
-        &installation.token_data.token,
+        &token,
         &webhook.repository.owner.name,
         &webhook.repository.name,
         &commit_data,
@@ -132,5 +204,154 @@ pub async fn increase_version(
     )
     .await?;
 
-    Ok(())
+    report.commit_sha = Some(commit_data.sha);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{app_apis::use_api_stub, storage::init_test_storage};
+    use axum::{
+        extract::Path,
+        http::{Method, StatusCode, Uri},
+        routing::{get, post},
+        Json, Router,
+    };
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use serde_json::{json, Value};
+    use std::sync::Mutex;
+    use tokio::net::TcpListener;
+
+    // Method and path of every call received by the API stub
+    static API_CALLS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    fn record(method: &Method, uri: &Uri) {
+        API_CALLS
+            .lock()
+            .unwrap()
+            .push(format!("{method} {}", uri.path()));
+    }
+
+    async fn access_token_stub(method: Method, uri: Uri) -> (StatusCode, Json<Value>) {
+        record(&method, &uri);
+        let expires_at = (Utc::now() + TimeDelta::hours(1)).to_rfc3339();
+        let token = json!({
+            "token": "stub-installation-token",
+            "expires_at": expires_at,
+            "permissions": { "contents": "write" },
+            "repository_selection": "selected",
+        });
+        (StatusCode::CREATED, Json(token))
+    }
+
+    async fn contents_stub(
+        Path((_, _, path)): Path<(String, String, String)>,
+        method: Method,
+        uri: Uri,
+        headers: axum::http::HeaderMap,
+    ) -> (StatusCode, Json<Value>) {
+        record(&method, &uri);
+        if headers
+            .get("authorization")
+            .is_none_or(|token| token != "Bearer stub-installation-token")
+        {
+            return (StatusCode::UNAUTHORIZED, Json(json!({})));
+        }
+        let content = STANDARD.encode("#pragma once\n#define VERSION \"1.4.2\"\n");
+        let file = json!({
+            "type": "file",
+            "encoding": "base64",
+            "size": content.len(),
+            "name": path,
+            "path": path,
+            "content": content,
+        });
+        (StatusCode::OK, Json(file))
+    }
+
+    // Trees, commits and refs would change the repository, they are only recorded
+    async fn write_stub(method: Method, uri: Uri) -> StatusCode {
+        record(&method, &uri);
+        StatusCode::NOT_FOUND
+    }
+
+    async fn start_api_stub() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let app = Router::new()
+            .route(
+                "/app/installations/:id/access_tokens",
+                post(access_token_stub),
+            )
+            .route("/repos/:owner/:repo/contents/*path", get(contents_stub))
+            .fallback(write_stub);
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        address
+    }
+
+    fn push_webhook(installation_id: u128) -> WebWebHook {
+        let user = json!({ "name": "dev", "email": "dev@example.com", "username": "dev" });
+        serde_json::from_value(json!({
+            "ref": "refs/heads/main",
+            "repository": {
+                "id": 1,
+                "name": "app",
+                "full_name": "owner/app",
+                "owner": { "id": 2, "name": "owner", "email": "owner@example.com" },
+            },
+            "pusher": { "name": "dev", "email": "dev@example.com" },
+            "sender": { "login": "dev", "id": 3, "type": "User" },
+            "installation": { "id": installation_id, "node_id": "node" },
+            "commits": [{
+                "id": "abc123",
+                "tree_id": "def456",
+                "message": "change",
+                "author": user,
+                "committer": user,
+                "added": [],
+                "removed": [],
+                "modified": ["version.hpp"],
+            }],
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn dry_run_reads_the_file_without_committing() {
+        init_test_storage();
+        use_api_stub(&start_api_stub().await);
+        let app_config = AppConfig {
+            app_id: 1,
+            private_signature: include_str!("../tests/fixtures/test_private_key.pem").to_string(),
+            ..AppConfig::default()
+        };
+        let repo_config = RepositoryConfig {
+            commit_when_sender_is_bot: false,
+            file_to_donwload: "version.hpp".to_string(),
+            pattern_version_to_search: "#define VERSION".to_string(),
+            branch_refs_to_observe: vec!["refs/heads/main".to_string()],
+            dry_run: true,
+        };
+
+        let report = increase_version(&app_config, &repo_config, push_webhook(9028))
+            .await
+            .unwrap();
+        assert!(report.dry_run);
+        assert_eq!(report.old_version, "1.4.2");
+        assert!(report
+            .diff
+            .contains(&format!("+#define VERSION \"{}\"", report.new_version)));
+        assert!(report.describe().starts_with("DRY RUN"));
+        assert_eq!(report.commit_sha, None);
+
+        // The token and the file are fetched, no tree, commit or ref is written
+        assert_eq!(
+            *API_CALLS.lock().unwrap(),
+            [
+                "POST /app/installations/9028/access_tokens",
+                "GET /repos/owner/app/contents/version.hpp",
+            ]
+        );
+    }
 }